@@ -0,0 +1,33 @@
+#![feature(test)]
+extern crate test;
+
+use simplexpr::dynval::DynVal;
+use std::collections::HashMap;
+use test::Bencher;
+
+/// An arithmetic-heavy expression where every intermediate value is a short number.
+const EXPR: &str = "(cpu * 100 / cores + mem_used / mem_total * 100) / 2 + round_num(temp - 32 * 5 / 9, 1) - 10 % 3";
+
+fn vars() -> HashMap<String, DynVal> {
+    let mut vars = HashMap::new();
+    vars.insert("cpu".to_string(), DynVal::from(3.5));
+    vars.insert("cores".to_string(), DynVal::from(8));
+    vars.insert("mem_used".to_string(), DynVal::from(6144));
+    vars.insert("mem_total".to_string(), DynVal::from(16384));
+    vars.insert("temp".to_string(), DynVal::from(140));
+    vars
+}
+
+#[bench]
+fn bench_arithmetic_eval(b: &mut Bencher) {
+    let expr = simplexpr::parse_string(0, EXPR).unwrap();
+    let vars = vars();
+    b.iter(|| expr.eval(&vars).unwrap());
+}
+
+#[bench]
+fn bench_arithmetic_compiled_eval(b: &mut Bencher) {
+    let expr = simplexpr::parse_string(0, EXPR).unwrap().compile();
+    let vars = vars();
+    b.iter(|| expr.eval(&vars).unwrap());
+}
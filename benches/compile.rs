@@ -0,0 +1,71 @@
+#![feature(test)]
+extern crate test;
+
+use simplexpr::dynval::DynVal;
+use std::collections::HashMap;
+use test::Bencher;
+
+const EXPR: &str = "x > 10 ? round((x + 2 * 3) / (4 - 1), 2) : \"value: \" + obj.values[x % 3]";
+
+fn vars() -> HashMap<String, DynVal> {
+    let mut vars = HashMap::new();
+    vars.insert("x".to_string(), DynVal::from(7));
+    vars.insert("obj".to_string(), DynVal::from(r#"{"values": [1, 2, 3]}"#));
+    vars.insert("values".to_string(), DynVal::from(format!("{:?}", (0..200).collect::<Vec<_>>())));
+    vars
+}
+
+#[bench]
+fn bench_eval(b: &mut Bencher) {
+    let expr = simplexpr::parse_string(0, EXPR).unwrap();
+    let vars = vars();
+    b.iter(|| expr.eval(&vars).unwrap());
+}
+
+#[bench]
+fn bench_compiled_eval(b: &mut Bencher) {
+    let expr = simplexpr::parse_string(0, EXPR).unwrap().compile();
+    let vars = vars();
+    b.iter(|| expr.eval(&vars).unwrap());
+}
+
+/// The same expensive subexpression repeated, as commonly results from expanding macros.
+const REPEATED_EXPR: &str = "sum(values) / 4 > 10 ? round(sum(values) / 4, 1) : to_json(sum(values) / 4)";
+
+#[bench]
+fn bench_eval_repeated(b: &mut Bencher) {
+    let expr = simplexpr::parse_string(0, REPEATED_EXPR).unwrap();
+    let vars = vars();
+    b.iter(|| expr.eval(&vars).unwrap());
+}
+
+#[bench]
+fn bench_compiled_eval_repeated(b: &mut Bencher) {
+    let expr = simplexpr::parse_string(0, REPEATED_EXPR).unwrap().compile();
+    let vars = vars();
+    b.iter(|| expr.eval(&vars).unwrap());
+}
+
+fn rows() -> Vec<HashMap<String, DynVal>> {
+    (0..100)
+        .map(|i| {
+            let mut vars = vars();
+            vars.insert("x".to_string(), DynVal::from(i));
+            vars
+        })
+        .collect()
+}
+
+#[bench]
+fn bench_eval_rows(b: &mut Bencher) {
+    let expr = simplexpr::parse_string(0, EXPR).unwrap();
+    let rows = rows();
+    b.iter(|| rows.iter().map(|row| expr.eval(row)).collect::<Vec<_>>());
+}
+
+#[bench]
+fn bench_eval_batch(b: &mut Bencher) {
+    let expr = simplexpr::parse_string(0, EXPR).unwrap();
+    let rows = rows();
+    b.iter(|| expr.eval_batch(&rows));
+}
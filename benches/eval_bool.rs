@@ -0,0 +1,32 @@
+#![feature(test)]
+extern crate test;
+
+use simplexpr::dynval::DynVal;
+use std::collections::HashMap;
+use test::Bencher;
+
+/// A typical condition deciding whether a widget is visible.
+const EXPR: &str = "!hidden && (volume > 0 || always_show) && device != \"none\"";
+
+fn vars() -> HashMap<String, DynVal> {
+    let mut vars = HashMap::new();
+    vars.insert("hidden".to_string(), DynVal::from(false));
+    vars.insert("volume".to_string(), DynVal::from(40));
+    vars.insert("always_show".to_string(), DynVal::from(false));
+    vars.insert("device".to_string(), DynVal::from("speakers"));
+    vars
+}
+
+#[bench]
+fn bench_eval_to_bool(b: &mut Bencher) {
+    let expr = simplexpr::parse_string(0, EXPR).unwrap();
+    let vars = vars();
+    b.iter(|| expr.eval_to_bool(&vars).unwrap());
+}
+
+#[bench]
+fn bench_eval_bool(b: &mut Bencher) {
+    let expr = simplexpr::parse_string(0, EXPR).unwrap();
+    let vars = vars();
+    b.iter(|| expr.eval_bool(&vars).unwrap());
+}
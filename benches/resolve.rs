@@ -0,0 +1,32 @@
+#![feature(test)]
+extern crate test;
+
+use simplexpr::{dynval::DynVal, SimplExpr};
+use std::collections::HashMap;
+use test::Bencher;
+
+/// Many expressions sharing the same variables, as found in a large config.
+fn exprs() -> Vec<SimplExpr> {
+    (0..500)
+        .map(|i| {
+            simplexpr::parse_string(0, &format!("volume_{0} > 50 ? label_{0} + volume_{0} : muted ?: label_{0}", i % 10)).unwrap()
+        })
+        .collect()
+}
+
+fn vars() -> HashMap<String, DynVal> {
+    let mut vars = HashMap::new();
+    for i in 0..10 {
+        vars.insert(format!("volume_{}", i), DynVal::from(i * 10));
+        vars.insert(format!("label_{}", i), DynVal::from(format!("vol {}: ", i)));
+    }
+    vars.insert("muted".to_string(), DynVal::from(false));
+    vars
+}
+
+#[bench]
+fn bench_clone_and_resolve_many(b: &mut Bencher) {
+    let exprs = exprs();
+    let vars = vars();
+    b.iter(|| exprs.iter().map(|expr| expr.clone().resolve_refs(&vars).unwrap()).collect::<Vec<_>>());
+}
@@ -19,7 +19,7 @@ impl std::fmt::Debug for Span {
 }
 
 #[rustfmt::skip]
-#[derive(Clone, PartialEq, Eq, Serialize, Deserialize, Debug, strum::EnumString, strum::Display)]
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize, Debug, strum::EnumString, strum::Display)]
 pub enum BinOp {
     #[strum(serialize = "+") ] Plus,
     #[strum(serialize = "-") ] Minus,
@@ -36,20 +36,44 @@ pub enum BinOp {
     #[strum(serialize = "=~")] RegexMatch,
 }
 
-#[derive(Clone, PartialEq, Eq, Serialize, Deserialize, Debug, strum::EnumString, strum::Display)]
+impl BinOp {
+    /// The precedence level of this operator, as used by the parser.
+    /// Lower values bind more tightly, so `a + b * c` parses as `a + (b * c)`.
+    pub fn precedence(&self) -> u8 {
+        match self {
+            BinOp::Times | BinOp::Div | BinOp::Mod => 3,
+            BinOp::Plus | BinOp::Minus => 4,
+            BinOp::Equals | BinOp::NotEquals | BinOp::GT | BinOp::LT | BinOp::RegexMatch => 5,
+            BinOp::And | BinOp::Or | BinOp::Elvis => 6,
+        }
+    }
+
+    /// Whether chains of operators of the same precedence level group to the left, i.e. `a - b - c` is `(a - b) - c`.
+    pub fn is_left_associative(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize, Debug, strum::EnumString, strum::Display)]
 pub enum UnaryOp {
     #[strum(serialize = "!")]
     Not,
 }
 
+/// The name of a referenced variable. Cheap to clone, so copying an expression does not reallocate its variable names.
+pub type VarName = std::sync::Arc<str>;
+
 #[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum SimplExpr {
     Literal(Span, DynVal),
-    VarRef(Span, String),
+    VarRef(Span, VarName),
     BinOp(Span, Box<SimplExpr>, BinOp, Box<SimplExpr>),
     UnaryOp(Span, UnaryOp, Box<SimplExpr>),
     IfElse(Span, Box<SimplExpr>, Box<SimplExpr>, Box<SimplExpr>),
     JsonAccess(Span, Box<SimplExpr>, Box<SimplExpr>),
+    /// Like `JsonAccess`, but evaluates to an empty value rather than erroring or returning `null`
+    /// if the value is empty, not indexable or does not contain the given index.
+    SafeAccess(Span, Box<SimplExpr>, Box<SimplExpr>),
     FunctionCall(Span, String, Vec<SimplExpr>),
 }
 
@@ -62,6 +86,7 @@ impl std::fmt::Display for SimplExpr {
             SimplExpr::UnaryOp(_, op, x) => write!(f, "{}{}", op, x),
             SimplExpr::IfElse(_, a, b, c) => write!(f, "(if {} then {} else {})", a, b, c),
             SimplExpr::JsonAccess(_, value, index) => write!(f, "{}[{}]", value, index),
+            SimplExpr::SafeAccess(_, value, index) => write!(f, "{}?[{}]", value, index),
             SimplExpr::FunctionCall(_, function_name, args) => {
                 write!(f, "{}({})", function_name, args.iter().join(", "))
             }
@@ -73,6 +98,46 @@ impl SimplExpr {
         Self::Literal(span, DynVal(s, Some(span)))
     }
 
+    /// Returns the value of this expression if it is a literal.
+    pub fn as_literal(&self) -> Option<&DynVal> {
+        match self {
+            SimplExpr::Literal(_, x) => Some(x),
+            _ => None,
+        }
+    }
+
+    /// Number of nodes in this expression tree, including this one.
+    pub fn node_count(&self) -> usize {
+        use SimplExpr::*;
+        match self {
+            Literal(..) | VarRef(..) => 1,
+            UnaryOp(_, _, x) => 1 + x.node_count(),
+            BinOp(_, a, _, b) | JsonAccess(_, a, b) | SafeAccess(_, a, b) => 1 + a.node_count() + b.node_count(),
+            IfElse(_, a, b, c) => 1 + a.node_count() + b.node_count() + c.node_count(),
+            FunctionCall(_, _, args) => 1 + args.iter().map(|x| x.node_count()).sum::<usize>(),
+        }
+    }
+
+    /// Call the given visitor on this expression and all of its subexpressions, parents before their children.
+    pub fn walk(&self, visitor: &mut impl FnMut(&SimplExpr)) {
+        use SimplExpr::*;
+        visitor(self);
+        match self {
+            Literal(..) | VarRef(..) => {}
+            UnaryOp(_, _, x) => x.walk(visitor),
+            BinOp(_, a, _, b) | JsonAccess(_, a, b) | SafeAccess(_, a, b) => {
+                a.walk(visitor);
+                b.walk(visitor);
+            }
+            IfElse(_, a, b, c) => {
+                a.walk(visitor);
+                b.walk(visitor);
+                c.walk(visitor);
+            }
+            FunctionCall(_, _, args) => args.iter().for_each(|x| x.walk(visitor)),
+        }
+    }
+
     pub fn span(&self) -> Span {
         match self {
             SimplExpr::Literal(span, _) => *span,
@@ -81,6 +146,7 @@ impl SimplExpr {
             SimplExpr::UnaryOp(span, ..) => *span,
             SimplExpr::IfElse(span, ..) => *span,
             SimplExpr::JsonAccess(span, ..) => *span,
+            SimplExpr::SafeAccess(span, ..) => *span,
             SimplExpr::FunctionCall(span, ..) => *span,
         }
     }
@@ -91,3 +157,37 @@ impl std::fmt::Debug for SimplExpr {
         write!(f, "{}", self)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_as_literal() {
+        let literal = crate::parse_string(0, "\"hi\"").unwrap();
+        assert_eq!(literal.as_literal().map(|x| x.0.as_str()), Some("hi"));
+        assert_eq!(crate::parse_string(0, "1 + 2").unwrap().as_literal(), None);
+        assert_eq!(crate::parse_string(0, "x").unwrap().as_literal(), None);
+    }
+
+    #[test]
+    fn test_node_count() {
+        assert_eq!(crate::parse_string(0, "x").unwrap().node_count(), 1);
+        assert_eq!(crate::parse_string(0, "1 + 2 * x").unwrap().node_count(), 5);
+        assert_eq!(crate::parse_string(0, "!a ? obj.b : f(1, 2, 3)").unwrap().node_count(), 10);
+    }
+
+    #[test]
+    fn test_walk() {
+        let expr = crate::parse_string(0, "!a ? obj.b : f(1, g(x), 3)").unwrap();
+        let mut count = 0;
+        expr.walk(&mut |_| count += 1);
+        assert_eq!(count, expr.node_count());
+
+        let mut visited = Vec::new();
+        expr.walk(&mut |x| match x {
+            super::SimplExpr::VarRef(_, name) => visited.push(name.to_string()),
+            super::SimplExpr::FunctionCall(_, name, _) => visited.push(format!("{}()", name)),
+            _ => {}
+        });
+        assert_eq!(visited, vec!["a", "obj", "f()", "g()", "x"]);
+    }
+}
@@ -0,0 +1,87 @@
+use crate::{
+    ast::{SimplExpr, VarName},
+    dynval::DynVal,
+    eval::{self, EvalError},
+};
+use itertools::Itertools;
+use std::collections::HashMap;
+
+/// An expression that remembers the result of its last evaluation, and only evaluates again
+/// once one of the variables it references has changed. Changes to any other variables are ignored.
+/// Expressions calling a non-pure function such as `random` are evaluated every time.
+#[derive(Clone, Debug)]
+pub struct CachedExpr {
+    expr: SimplExpr,
+    var_refs: Vec<VarName>,
+    is_pure: bool,
+    /// The values of the referenced variables during the last evaluation, in the order of `var_refs`, and its result.
+    last: Option<(Vec<Option<DynVal>>, DynVal)>,
+}
+
+impl CachedExpr {
+    pub fn new(expr: SimplExpr) -> Self {
+        let var_refs = expr.var_refs().into_iter().unique().cloned().collect();
+        let is_pure = !eval::IMPURE_FUNCTIONS.iter().any(|f| expr.contains_function(f));
+        CachedExpr { expr, var_refs, is_pure, last: None }
+    }
+
+    pub fn expr(&self) -> &SimplExpr {
+        &self.expr
+    }
+
+    /// Evaluate the expression, reusing the last result if none of the referenced variables changed since.
+    /// Failed evaluations are not cached.
+    pub fn eval(&mut self, values: &HashMap<String, DynVal>) -> Result<DynVal, EvalError> {
+        if let Some((inputs, result)) = &self.last {
+            let unchanged = self.var_refs.iter().zip(inputs).all(|(name, input)| match (values.get(&**name), input) {
+                (Some(value), Some(input)) => value.value_eq(input),
+                (None, None) => true,
+                _ => false,
+            });
+            if unchanged {
+                return Ok(result.clone());
+            }
+        }
+        self.last = None;
+        let result = self.expr.eval(values)?;
+        if self.is_pure {
+            let inputs = self.var_refs.iter().map(|name| values.get(&**name).cloned()).collect();
+            self.last = Some((inputs, result.clone()));
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maplit::hashmap;
+
+    #[test]
+    fn test_cached_expr() {
+        let mut cached = CachedExpr::new(crate::parse_string(0, "a * 2 + a").unwrap());
+        let mut values = hashmap! { "a".to_string() => DynVal::from(1), "b".to_string() => DynVal::from(1) };
+        assert_eq!(cached.eval(&values).unwrap().0, "3");
+
+        // replace the cached result, to be able to tell whether it is used.
+        cached.last.as_mut().unwrap().1 = DynVal::from("cached");
+        values.insert("b".to_string(), DynVal::from(2));
+        assert_eq!(cached.eval(&values).unwrap().0, "cached");
+        values.remove("b");
+        assert_eq!(cached.eval(&values).unwrap().0, "cached");
+
+        values.insert("a".to_string(), DynVal::from(2));
+        assert_eq!(cached.eval(&values).unwrap().0, "6");
+        values.remove("a");
+        assert!(cached.eval(&values).is_err());
+        assert!(cached.last.is_none());
+    }
+
+    #[test]
+    fn test_impure_expressions_are_not_cached() {
+        let mut cached = CachedExpr::new(crate::parse_string(0, "try(\"a\", 0)").unwrap());
+        assert_eq!(cached.eval(&hashmap! {}).unwrap().0, "0");
+        assert!(cached.last.is_none());
+        assert_eq!(cached.eval(&hashmap! { "a".to_string() => DynVal::from(1) }).unwrap().0, "1");
+    }
+}
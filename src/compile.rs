@@ -0,0 +1,452 @@
+use crate::{
+    ast::{BinOp, SimplExpr, Span, UnaryOp, VarName},
+    dynval::DynVal,
+    eval::{self, EvalError, EvalOptions},
+};
+use std::collections::HashMap;
+
+/// A single step of a [`CompiledExpr`], operating on a stack of values.
+#[derive(Clone, Debug)]
+enum Instr {
+    Push(DynVal),
+    Load(Span, VarName),
+    BinOp(Span, BinOp),
+    UnaryOp(Span, UnaryOp),
+    JsonAccess(Span),
+    SafeAccess(Span),
+    Call(Span, String, usize),
+    /// Pop the condition, jumping to the given instruction if it is false.
+    JumpIfFalse(usize),
+    Jump(usize),
+    /// Attach the given span to the value on top of the stack.
+    At(Span),
+    /// If the given cache slot is filled, push its value with the given span and jump to the given instruction.
+    /// Otherwise, continue with evaluating the subexpression.
+    LoadCached(usize, Span, usize),
+    /// Store a copy of the value on top of the stack in the given cache slot.
+    StoreCached(usize),
+}
+
+/// An expression that has been flattened into a list of instructions, to be evaluated repeatedly without walking the AST.
+/// Evaluation behaves the same as [`SimplExpr::eval`].
+#[derive(Clone, Debug)]
+pub struct CompiledExpr {
+    instrs: Vec<Instr>,
+    cache_slots: usize,
+}
+
+impl SimplExpr {
+    /// Compile this expression into a [`CompiledExpr`].
+    /// Subexpressions that do not reference any variables are evaluated once during compilation,
+    /// unless they call a non-pure function such as `random`.
+    /// Subexpressions that occur multiple times are only evaluated once per evaluation of the compiled expression.
+    pub fn compile(self) -> CompiledExpr {
+        let mut analysis = Analysis::default();
+        analysis.analyze(&self);
+        let mut compiler = Compiler { analysis, next: 0, instrs: Vec::new(), cache_slots: HashMap::new() };
+        compiler.compile(self);
+        CompiledExpr { instrs: compiler.instrs, cache_slots: compiler.cache_slots.len() }
+    }
+
+    /// Evaluate this expression against each of the given sets of variables.
+    /// The expression is compiled once up front, which makes this faster than calling [`SimplExpr::eval`] for each row.
+    pub fn eval_batch(&self, rows: &[HashMap<String, DynVal>]) -> Vec<Result<DynVal, EvalError>> {
+        let compiled = self.clone().compile();
+        rows.iter().map(|row| compiled.eval(row)).collect()
+    }
+}
+
+/// The structure of a single node, ignoring its span, with its children identified by their [`NodeInfo::id`].
+#[derive(PartialEq, Eq, Hash)]
+enum NodeKey {
+    Literal(String),
+    VarRef(VarName),
+    BinOp(usize, BinOp, usize),
+    UnaryOp(UnaryOp, usize),
+    IfElse(usize, usize, usize),
+    JsonAccess(usize, usize),
+    SafeAccess(usize, usize),
+    FunctionCall(String, Vec<usize>),
+}
+
+/// What the compiler knows about a single subexpression.
+struct NodeInfo {
+    /// Equal subexpressions get the same id, regardless of their spans.
+    id: usize,
+    /// The number of nodes in the subexpression, including itself.
+    size: usize,
+    /// Whether the subexpression calls none of the [`eval::IMPURE_FUNCTIONS`].
+    pure: bool,
+    /// The value of the subexpression, if it could already be evaluated during compilation.
+    value: Option<DynVal>,
+}
+
+/// Information about every subexpression of an expression, computed bottom-up in a single pass over it.
+#[derive(Default)]
+struct Analysis {
+    /// The subexpressions in pre-order, which is the order the [`Compiler`] visits them in.
+    nodes: Vec<NodeInfo>,
+    ids: HashMap<NodeKey, usize>,
+    /// How often the subexpressions with each id occur.
+    occurrences: Vec<usize>,
+}
+
+impl Analysis {
+    /// Analyze the given expression and all of its subexpressions, returning the index of its [`NodeInfo`].
+    fn analyze(&mut self, expr: &SimplExpr) -> usize {
+        use SimplExpr::*;
+        let index = self.nodes.len();
+        self.nodes.push(NodeInfo { id: 0, size: 0, pure: true, value: None });
+        let (key, children, value) = match expr {
+            Literal(_, x) => (NodeKey::Literal(x.0.clone()), vec![], Some(x.clone())),
+            VarRef(_, name) => (NodeKey::VarRef(name.clone()), vec![], None),
+            BinOp(_, a, op, b) => {
+                let (a, b) = (self.analyze(a), self.analyze(b));
+                let value = self.value(a).zip(self.value(b)).and_then(|(a, b)| eval::eval_bin_op(a, op, b).ok());
+                (NodeKey::BinOp(self.id(a), op.clone(), self.id(b)), vec![a, b], value)
+            }
+            UnaryOp(_, op, a) => {
+                let a = self.analyze(a);
+                let value = self.value(a).and_then(|a| eval::eval_unary_op(op, a).ok());
+                (NodeKey::UnaryOp(op.clone(), self.id(a)), vec![a], value)
+            }
+            IfElse(_, cond, yes, no) => {
+                let (cond, yes, no) = (self.analyze(cond), self.analyze(yes), self.analyze(no));
+                let value = match self.value(cond).map(|x| x.as_bool()) {
+                    Some(Ok(true)) => self.value(yes),
+                    Some(Ok(false)) => self.value(no),
+                    _ => None,
+                };
+                (NodeKey::IfElse(self.id(cond), self.id(yes), self.id(no)), vec![cond, yes, no], value)
+            }
+            JsonAccess(span, val, index) => {
+                let (val, index) = (self.analyze(val), self.analyze(index));
+                // strict indexing fails wherever other options would change the result,
+                // so a value that passes it is the same under any options.
+                let strict = EvalOptions::new().with_strict_indexing(true);
+                let value = self
+                    .value(val)
+                    .zip(self.value(index))
+                    .and_then(|(val, index)| eval::eval_indexing(*span, val, index, &strict).ok());
+                (NodeKey::JsonAccess(self.id(val), self.id(index)), vec![val, index], value)
+            }
+            SafeAccess(_, val, index) => {
+                let (val, index) = (self.analyze(val), self.analyze(index));
+                let value = self.value(val).zip(self.value(index)).map(|(val, index)| eval::eval_safe_access(val, index));
+                (NodeKey::SafeAccess(self.id(val), self.id(index)), vec![val, index], value)
+            }
+            FunctionCall(_, name, args) => {
+                let args = args.iter().map(|arg| self.analyze(arg)).collect::<Vec<_>>();
+                let value = if eval::IMPURE_FUNCTIONS.contains(&name.as_str()) {
+                    None
+                } else {
+                    let values = args.iter().map(|arg| self.value(*arg)).collect::<Option<Vec<_>>>();
+                    values.and_then(|values| eval::call_expr_function(name, values, &EvalOptions::default()).ok())
+                };
+                (NodeKey::FunctionCall(name.clone(), args.iter().map(|arg| self.id(*arg)).collect()), args, value)
+            }
+        };
+        let next_id = self.ids.len();
+        let id = *self.ids.entry(key).or_insert(next_id);
+        if id == self.occurrences.len() {
+            self.occurrences.push(0);
+        }
+        self.occurrences[id] += 1;
+        let impure_call = matches!(expr, FunctionCall(_, name, _) if eval::IMPURE_FUNCTIONS.contains(&name.as_str()));
+        let pure = !impure_call && children.iter().all(|child| self.nodes[*child].pure);
+        let size = self.nodes.len() - index;
+        self.nodes[index] = NodeInfo { id, size, pure, value: value.map(|x| x.at(expr.span())) };
+        index
+    }
+
+    fn id(&self, index: usize) -> usize {
+        self.nodes[index].id
+    }
+
+    fn value(&self, index: usize) -> Option<DynVal> {
+        self.nodes[index].value.clone()
+    }
+}
+
+struct Compiler {
+    analysis: Analysis,
+    /// The index of the [`NodeInfo`] of the next subexpression to be compiled.
+    next: usize,
+    instrs: Vec<Instr>,
+    /// The cache slot of each subexpression id that occurs multiple times.
+    cache_slots: HashMap<usize, usize>,
+}
+
+impl Compiler {
+    fn compile(&mut self, expr: SimplExpr) {
+        let node = &mut self.analysis.nodes[self.next];
+        if let Some(value) = node.value.take() {
+            self.next += node.size;
+            self.instrs.push(Instr::Push(value));
+            return;
+        }
+        let (id, pure) = (node.id, node.pure);
+        self.next += 1;
+        // literals always have a value, and variables are as cheap to look up as the cache.
+        if self.analysis.occurrences[id] > 1 && pure && !matches!(expr, SimplExpr::VarRef(..)) {
+            let next_slot = self.cache_slots.len();
+            let slot = *self.cache_slots.entry(id).or_insert(next_slot);
+            let load_cached = self.instrs.len();
+            self.instrs.push(Instr::LoadCached(slot, expr.span(), 0));
+            self.compile_uncached(expr);
+            self.instrs.push(Instr::StoreCached(slot));
+            self.instrs[load_cached] = match self.instrs[load_cached] {
+                Instr::LoadCached(slot, span, _) => Instr::LoadCached(slot, span, self.instrs.len()),
+                _ => unreachable!(),
+            };
+        } else {
+            self.compile_uncached(expr);
+        }
+    }
+
+    fn compile_uncached(&mut self, expr: SimplExpr) {
+        use SimplExpr::*;
+        match expr {
+            Literal(span, x) => self.instrs.push(Instr::Push(x.at(span))),
+            VarRef(span, name) => self.instrs.push(Instr::Load(span, name)),
+            BinOp(span, box a, op, box b) => {
+                self.compile(a);
+                self.compile(b);
+                self.instrs.push(Instr::BinOp(span, op));
+            }
+            UnaryOp(span, op, box a) => {
+                self.compile(a);
+                self.instrs.push(Instr::UnaryOp(span, op));
+            }
+            IfElse(span, box cond, box yes, box no) => {
+                self.compile(cond);
+                let jump_to_no = self.instrs.len();
+                self.instrs.push(Instr::JumpIfFalse(0));
+                self.compile(yes);
+                let jump_to_end = self.instrs.len();
+                self.instrs.push(Instr::Jump(0));
+                self.instrs[jump_to_no] = Instr::JumpIfFalse(self.instrs.len());
+                self.compile(no);
+                self.instrs[jump_to_end] = Instr::Jump(self.instrs.len());
+                self.instrs.push(Instr::At(span));
+            }
+            JsonAccess(span, box val, box index) => {
+                self.compile(val);
+                self.compile(index);
+                self.instrs.push(Instr::JsonAccess(span));
+            }
+            SafeAccess(span, box val, box index) => {
+                self.compile(val);
+                self.compile(index);
+                self.instrs.push(Instr::SafeAccess(span));
+            }
+            FunctionCall(span, name, args) => {
+                let arg_count = args.len();
+                for arg in args {
+                    self.compile(arg);
+                }
+                self.instrs.push(Instr::Call(span, name, arg_count));
+            }
+        }
+    }
+}
+
+impl CompiledExpr {
+    pub fn eval(&self, values: &HashMap<String, DynVal>) -> Result<DynVal, EvalError> {
+        self.eval_with_options(values, &EvalOptions::default())
+    }
+
+    /// Evaluate the expression with the given options, behaving the same as [`SimplExpr::eval_with_options`].
+    /// Calls to builtins that were already evaluated during compilation are not affected by functions registered
+    /// under the same name.
+    pub fn eval_with_options(&self, values: &HashMap<String, DynVal>, options: &EvalOptions) -> Result<DynVal, EvalError> {
+        let mut stack: Vec<DynVal> = Vec::new();
+        let mut cache: Vec<Option<DynVal>> = vec![None; self.cache_slots];
+        let mut pc = 0;
+        while let Some(instr) = self.instrs.get(pc) {
+            pc += 1;
+            match instr {
+                Instr::Push(x) => stack.push(x.clone()),
+                Instr::Load(span, name) => {
+                    let value = values.get(&**name).ok_or_else(|| EvalError::UnresolvedVariable(name.to_string()).at(*span))?;
+                    stack.push(value.clone().at(*span));
+                }
+                Instr::BinOp(span, op) => {
+                    let b = pop(&mut stack);
+                    let a = pop(&mut stack);
+                    stack.push(eval::eval_bin_op(a, op, b)?.at(*span));
+                }
+                Instr::UnaryOp(span, op) => {
+                    let a = pop(&mut stack);
+                    stack.push(eval::eval_unary_op(op, a)?.at(*span));
+                }
+                Instr::JsonAccess(span) => {
+                    let index = pop(&mut stack);
+                    let val = pop(&mut stack);
+                    stack.push(eval::eval_indexing(*span, val, index, options)?.at(*span));
+                }
+                Instr::SafeAccess(span) => {
+                    let index = pop(&mut stack);
+                    let val = pop(&mut stack);
+                    stack.push(eval::eval_safe_access(val, index).at(*span));
+                }
+                Instr::Call(span, name, arg_count) => {
+                    let args = stack.split_off(stack.len() - arg_count);
+                    let result = eval::eval_function_call(name, args, values, options);
+                    stack.push(result.map_err(|e| e.at(*span))?.at(*span));
+                }
+                Instr::JumpIfFalse(target) => {
+                    if !pop(&mut stack).as_bool()? {
+                        pc = *target;
+                    }
+                }
+                Instr::Jump(target) => pc = *target,
+                Instr::At(span) => {
+                    let value = pop(&mut stack);
+                    stack.push(value.at(*span));
+                }
+                Instr::LoadCached(slot, span, target) => {
+                    if let Some(value) = &cache[*slot] {
+                        stack.push(value.clone().at(*span));
+                        pc = *target;
+                    }
+                }
+                Instr::StoreCached(slot) => cache[*slot] = stack.last().cloned(),
+            }
+        }
+        Ok(pop(&mut stack))
+    }
+}
+
+fn pop(stack: &mut Vec<DynVal>) -> DynVal {
+    stack.pop().expect("compiled expression popped from an empty stack")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use itertools::Itertools;
+    use maplit::hashmap;
+
+    #[test]
+    fn test_compiled_matches_eval() {
+        let vars = hashmap! {
+            "x".to_string() => DynVal::from(3),
+            "obj".to_string() => DynVal::from(r#"{"a": [1, 2, 3]}"#),
+        };
+        let inputs = [
+            "x + 2 * 4",
+            "(1 + 2) * x",
+            "x > 2 ? \"big\" : \"small\"",
+            "x < 2 ? \"big\" : x == 3 ? \"three\" : \"other\"",
+            "obj.a[x - 1]",
+            "\"~\" + round(x / 7, 2)",
+            "!(x == 3) || true",
+            "\"\" ?: x",
+            "obj?.a?[1]",
+            "obj?.missing?.deeper ?: \"default\"",
+            "try(\"x * 2\", 0)",
+            "obj.a[x - 1] * 2 > 5 ? obj.a[x - 1] * 2 : obj.a[x - 1]",
+            "round(x / 7, 2) + round(x / 7, 2)",
+        ];
+        for input in inputs.iter() {
+            let expr = crate::parse_string(0, input).unwrap();
+            let expected = expr.eval(&vars).unwrap();
+            let actual = expr.compile().eval(&vars).unwrap();
+            assert!(expected.value_eq(&actual), "{}: {} != {}", input, expected, actual);
+            assert_eq!(expected.span(), actual.span(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_eval_batch() {
+        let expr = crate::parse_string(0, "x > 2 ? obj.a[x - 3] * (1 + 2) : \"small\"").unwrap();
+        let obj = DynVal::from(r#"{"a": [1, 2, 3]}"#);
+        let rows = (0..8)
+            .map(|x| hashmap! { "x".to_string() => DynVal::from(x), "obj".to_string() => obj.clone() })
+            .collect_vec();
+        let batch = expr.eval_batch(&rows);
+        assert_eq!(batch.len(), rows.len());
+        for (row, result) in rows.iter().zip(batch) {
+            match (expr.eval(row), result) {
+                (Ok(expected), Ok(actual)) => assert!(expected.value_eq(&actual), "{} != {}", expected, actual),
+                (Err(_), Err(_)) => {}
+                (expected, actual) => panic!("{:?} != {:?}", expected, actual),
+            }
+        }
+        assert!(expr.eval_batch(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_constant_folding() {
+        let compiled = crate::parse_string(0, "(1 + 2) * x").unwrap().compile();
+        assert_eq!(compiled.instrs.len(), 3);
+        assert!(matches!(&compiled.instrs[0], Instr::Push(x) if x.0 == "3"));
+    }
+
+    #[test]
+    fn test_common_subexpressions_are_cached() {
+        let compiled = crate::parse_string(0, "(x * 2 + 1) + (x * 2 + 1) * (x*2 + 1)").unwrap().compile();
+        assert_eq!(compiled.cache_slots, 2);
+        assert_eq!(compiled.eval(&hashmap! { "x".to_string() => DynVal::from(1) }).unwrap().0, "12");
+
+        let compiled = crate::parse_string(0, "random() + random()").unwrap().compile();
+        assert_eq!(compiled.cache_slots, 0);
+
+        // the first occurrence is in the branch that is not taken, so the cache is still empty for the second one.
+        let compiled = crate::parse_string(0, "x > 1 ? round(x / 3, 1) : round(x / 3, 1) + 1").unwrap().compile();
+        assert_eq!(compiled.eval(&hashmap! { "x".to_string() => DynVal::from(1) }).unwrap().0, "1.3");
+    }
+
+    #[test]
+    fn test_cse_distinguishes_literals_containing_quotes() {
+        // both calls print as `choose(i, "a", "b")`, but must not share a cache slot.
+        let expr = crate::parse_string(0, "choose(i, \"a\", \"b\") + \"|\" + choose(i, x)").unwrap();
+        let expr = expr.bind("x", DynVal::from(r#"a", "b"#));
+        let vars = hashmap! { "i".to_string() => DynVal::from(0) };
+        let compiled = expr.clone().compile();
+        assert_eq!(compiled.cache_slots, 0);
+        assert_eq!(compiled.eval(&vars).unwrap().0, expr.eval(&vars).unwrap().0);
+        assert_eq!(compiled.eval(&vars).unwrap().0, r#"a|a", "b"#);
+    }
+
+    #[test]
+    fn test_no_folding_of_impure_functions() {
+        let compiled = crate::parse_string(0, "random() * 2").unwrap().compile();
+        assert!(matches!(compiled.instrs.last(), Some(Instr::BinOp(..))));
+
+        let compiled = crate::parse_string(0, "debug(1 + 1, \"x\") + debug(1 + 1, \"x\")").unwrap().compile();
+        assert_eq!(compiled.cache_slots, 0);
+        let calls = std::cell::RefCell::new(Vec::new());
+        let callback = |label: &str, value: &DynVal| calls.borrow_mut().push((label.to_string(), value.0.clone()));
+        let options = EvalOptions::new().with_debug_callback(&callback);
+        assert_eq!(compiled.eval_with_options(&hashmap! {}, &options).unwrap().0, "4");
+        assert_eq!(calls.into_inner(), vec![("x".to_string(), "2".to_string()); 2]);
+    }
+
+    #[test]
+    fn test_compiled_eval_with_options() {
+        let mut registry = eval::FunctionRegistry::new();
+        registry.register("double", |args| Ok(DynVal::from(args[0].as_f64()? * 2.0)));
+        let vars = hashmap! { "x".to_string() => DynVal::from(3), "arr".to_string() => DynVal::from("[1, 2]") };
+        let compiled = crate::parse_string(0, "x[0] + double(x) + try(\"double(x)\", 0)").unwrap().compile();
+        assert!(compiled.eval(&vars).is_err());
+        let options = EvalOptions::new().with_registry(&registry).with_lenient_indexing(true);
+        assert_eq!(compiled.eval_with_options(&vars, &options).unwrap().0, "12");
+
+        let compiled = crate::parse_string(0, "arr[5] + \"[1, 2]\"[5]").unwrap().compile();
+        assert_eq!(compiled.eval(&vars).unwrap().0, "nullnull");
+        let strict = EvalOptions::new().with_strict_indexing(true);
+        assert!(compiled.eval_with_options(&vars, &strict).is_err());
+        let compiled = crate::parse_string(0, "\"[1, 2]\"[5]").unwrap().compile();
+        assert!(compiled.eval_with_options(&vars, &strict).is_err());
+    }
+
+    #[test]
+    fn test_compiled_errors() {
+        let compiled = crate::parse_string(0, "x + unknown(1)").unwrap().compile();
+        assert!(matches!(compiled.eval(&hashmap! {}), Err(EvalError::Spanned(_, _))));
+        let compiled = crate::parse_string(0, "1 + \"a\" * 2").unwrap().compile();
+        assert!(compiled.eval(&hashmap! {}).is_err());
+    }
+}
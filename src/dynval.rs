@@ -1,20 +1,24 @@
 use crate::ast::Span;
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
-use std::{fmt, iter::FromIterator, str::FromStr};
+use std::{collections::HashMap, fmt, iter::FromIterator, str::FromStr};
 
 pub type Result<T> = std::result::Result<T, ConversionError>;
 
 #[derive(Debug, thiserror::Error)]
-#[error("Failed to turn {value} into a value of type {target_type}")]
+#[error("Failed to turn {value} into a value of type {target_type}{}", fmt_source(.source))]
 pub struct ConversionError {
     pub value: DynVal,
     pub target_type: &'static str,
     pub source: Option<Box<dyn std::error::Error>>,
 }
 
+fn fmt_source(source: &Option<Box<dyn std::error::Error>>) -> String {
+    source.as_ref().map(|e| format!(": {}", e)).unwrap_or_default()
+}
+
 impl ConversionError {
-    fn new(value: DynVal, target_type: &'static str, source: impl std::error::Error + 'static) -> Self {
+    pub(crate) fn new(value: DynVal, target_type: &'static str, source: impl std::error::Error + 'static) -> Self {
         ConversionError { value, target_type, source: Some(Box::new(source)) }
     }
 
@@ -37,23 +41,31 @@ impl fmt::Display for DynVal {
         write!(f, "{}", self.0)
     }
 }
+/// Shows the value as a quoted string, followed by its span if it has one: `DynVal("value" @ 1..6)`.
 impl fmt::Debug for DynVal {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "\"{}\"", self.0)
+        match self.1 {
+            Some(span) => write!(f, "DynVal({:?} @ {})", self.0, span),
+            None => write!(f, "DynVal({:?})", self.0),
+        }
     }
 }
 
 /// Manually implement equality, to allow for values in different formats (i.e. "1" and "1.0") to still be considered as equal.
+/// The span of the values is never considered, only their contents.
 impl std::cmp::PartialEq<Self> for DynVal {
     fn eq(&self, other: &Self) -> bool {
-        if let (Ok(a), Ok(b)) = (self.as_f64(), other.as_f64()) {
-            a == b
-        } else {
-            self.0 == other.0
-        }
+        self.coerce_like(other) == other.coerce_like(self)
     }
 }
 
+/// A value in a form in which it can be compared with another value, as returned by [`DynVal::coerce_like`].
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum Comparable<'a> {
+    Number(f64),
+    String(&'a str),
+}
+
 impl FromIterator<DynVal> for DynVal {
     fn from_iter<T: IntoIterator<Item = DynVal>>(iter: T) -> Self {
         DynVal(iter.into_iter().join(""), None)
@@ -91,7 +103,23 @@ macro_rules! impl_dynval_from {
     };
 }
 
-impl_dynval_from!(bool, i32, u32, f32, u8, f64, &str);
+impl_dynval_from!(bool, i32, u32, i64, u64, usize, f32, u8, &str);
+
+/// Floats are stored in their shortest representation that parses back to the same value, so no precision is lost.
+/// The only exception are values within a few units in the last place of a number with at most 15 significant digits,
+/// which are stored as that number instead. This hides the rounding artifacts of floating point arithmetic, such as
+/// `0.1 + 0.2` resulting in `0.30000000000000004`, while changing the value by no more than the arithmetic itself may
+/// have been off. Values like `1 / 3` keep their full precision, so that errors do not compound across operations.
+impl From<f64> for DynVal {
+    fn from(x: f64) -> Self {
+        if x.fract() == 0.0 || !x.is_finite() {
+            return DynVal(x.to_string(), None);
+        }
+        let rounded: f64 = format!("{:.14e}", x).parse().unwrap_or(x);
+        let ulp = f64::from_bits(x.abs().to_bits() + 1) - x.abs();
+        DynVal(if (rounded - x).abs() <= 4.0 * ulp { rounded } else { x }.to_string(), None)
+    }
+}
 
 impl From<&serde_json::Value> for DynVal {
     fn from(v: &serde_json::Value) -> Self {
@@ -105,6 +133,36 @@ impl From<&serde_json::Value> for DynVal {
     }
 }
 
+impl From<Vec<DynVal>> for DynVal {
+    fn from(v: Vec<DynVal>) -> Self {
+        let values = v.into_iter().map(DynVal::into_json_value).collect();
+        DynVal::from(&serde_json::Value::Array(values))
+    }
+}
+
+impl From<HashMap<String, DynVal>> for DynVal {
+    fn from(v: HashMap<String, DynVal>) -> Self {
+        let values = v.into_iter().map(|(k, v)| (k, v.into_json_value())).collect();
+        DynVal::from(&serde_json::Value::Object(values))
+    }
+}
+
+/// Arithmetic on values follows the same rules as the operators in expressions. In particular, `+` concatenates the
+/// values as strings if the left one is not a number.
+macro_rules! impl_dynval_op {
+    ($($trait:ident, $method:ident => $op:ident),*) => {
+        $(impl std::ops::$trait for DynVal {
+            type Output = std::result::Result<DynVal, crate::eval::EvalError>;
+
+            fn $method(self, other: DynVal) -> Self::Output {
+                crate::eval::eval_bin_op(self, &crate::ast::BinOp::$op, other)
+            }
+        })*
+    };
+}
+
+impl_dynval_op!(Add, add => Plus, Sub, sub => Minus, Mul, mul => Times);
+
 impl DynVal {
     pub fn at(self, span: Span) -> Self {
         DynVal(self.0, Some(span))
@@ -114,10 +172,42 @@ impl DynVal {
         self.1
     }
 
+    /// Coerce this value into a form in which it can be compared with `other`:
+    /// a number if both values are numeric, and a string otherwise.
+    /// This is used by all comparisons, such as `==`, `<` and sorting, so that they agree with each other.
+    pub fn coerce_like(&self, other: &DynVal) -> Comparable<'_> {
+        match (self.as_f64(), other.as_f64()) {
+            (Ok(a), Ok(_)) => Comparable::Number(a),
+            _ => Comparable::String(&self.0),
+        }
+    }
+
+    /// Compare the exact contents of two values, ignoring their spans.
+    /// Unlike `==`, this does not consider numbers in different formats (i.e. "1" and "1.0") to be equal.
+    pub fn value_eq(&self, other: &DynVal) -> bool {
+        self.0 == other.0
+    }
+
+    /// The empty value, which is what the `?:` operator falls back on.
+    /// There is no separate notion of null: a json `null` is represented by the string `"null"`, which is not empty.
+    pub fn empty() -> Self {
+        DynVal(String::new(), None)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
     pub fn from_string(s: String) -> Self {
         DynVal(s, None)
     }
 
+    /// Booleans are always represented as `true` or `false`, which is what [`DynVal::as_bool`] accepts.
+    /// This is equivalent to `DynVal::from(b)`.
+    pub fn from_bool(b: bool) -> Self {
+        DynVal::from(b)
+    }
+
     pub fn read_as<E, T: FromDynVal<Err = E>>(&self) -> std::result::Result<T, E> {
         T::from_dynval(self)
     }
@@ -126,23 +216,58 @@ impl DynVal {
         self.0
     }
 
-    /// This will never fail
+    /// This will never fail, as every value is stored as a string.
+    /// Prefer [`DynVal::as_str`] where a `Result` is not needed.
     pub fn as_string(&self) -> Result<String> {
         Ok(self.0.to_owned())
     }
 
+    /// The string contents of this value.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Apply a function to the string contents of this value, keeping its span.
+    pub fn map_string(self, f: impl FnOnce(String) -> String) -> Result<DynVal> {
+        let span = self.1;
+        Ok(DynVal(f(self.as_string()?), span))
+    }
+
     pub fn as_f64(&self) -> Result<f64> {
         self.0.parse().map_err(|e| ConversionError::new(self.clone(), "f64", e))
     }
 
+    /// Parse a percentage like `50%` into a fraction (`0.5`). A plain number is returned as is.
+    pub fn as_percentage(&self) -> Result<f64> {
+        match self.0.strip_suffix('%') {
+            Some(percentage) => {
+                percentage.parse::<f64>().map(|x| x / 100.0).map_err(|e| ConversionError::new(self.clone(), "percentage", e))
+            }
+            None => self.0.parse().map_err(|e| ConversionError::new(self.clone(), "percentage", e)),
+        }
+    }
+
     pub fn as_i32(&self) -> Result<i32> {
         self.0.parse().map_err(|e| ConversionError::new(self.clone(), "i32", e))
     }
 
+    pub fn as_i64(&self) -> Result<i64> {
+        self.0.parse().map_err(|e| ConversionError::new(self.clone(), "i64", e))
+    }
+
     pub fn as_bool(&self) -> Result<bool> {
         self.0.parse().map_err(|e| ConversionError::new(self.clone(), "bool", e))
     }
 
+    /// Succeeds only if the value consists of exactly one unicode scalar value.
+    pub fn as_char(&self) -> Result<char> {
+        let mut chars = self.0.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => Ok(c),
+            _ => Err(ConversionError { value: self.clone(), target_type: "char", source: None }),
+        }
+    }
+
     pub fn as_duration(&self) -> Result<std::time::Duration> {
         use std::time::Duration;
         let s = &self.0;
@@ -188,13 +313,181 @@ impl DynVal {
 
     pub fn as_json_value(&self) -> Result<serde_json::Value> {
         serde_json::from_str::<serde_json::Value>(&self.0)
-            .map_err(|e| ConversionError::new(self.clone(), "json-value", Box::new(e)))
+            .map_err(|e| ConversionError::new(self.clone(), "json-value", e))
+    }
+
+    /// Like [`DynVal::as_json_value`], but returns `None` if the value is not valid json.
+    pub fn try_as_json_value(&self) -> Option<serde_json::Value> {
+        serde_json::from_str(&self.0).ok()
+    }
+
+    /// Turn this value into a json value to be embedded into a json array or object.
+    /// Values that are valid json (numbers, booleans, arrays, objects) are nested as such, anything else becomes a json string.
+    pub(crate) fn into_json_value(self) -> serde_json::Value {
+        serde_json::from_str(&self.0).unwrap_or(serde_json::Value::String(self.0))
     }
 }
 
 #[cfg(test)]
 mod test {
-    // use super::*;
+    use super::*;
+    use maplit::hashmap;
+
+    #[test]
+    fn test_from_vec() {
+        let value = DynVal::from(vec![DynVal::from(1), DynVal::from("hi"), DynVal::from(true)]);
+        assert_eq!(value.0, r#"[1,"hi",true]"#);
+        assert_eq!(value.as_json_value().unwrap(), serde_json::json!([1, "hi", true]));
+    }
+
+    #[test]
+    fn test_from_hashmap() {
+        let value = DynVal::from(hashmap! {
+            "a".to_string() => DynVal::from(1),
+            "b".to_string() => DynVal::from("hi"),
+        });
+        assert_eq!(value.as_json_value().unwrap(), serde_json::json!({"a": 1, "b": "hi"}));
+    }
+
+    #[test]
+    fn test_from_nested() {
+        let inner = DynVal::from(vec![DynVal::from(1), DynVal::from(2)]);
+        let object = DynVal::from(hashmap! { "inner".to_string() => inner });
+        let value = DynVal::from(vec![object, DynVal::from(vec![])]);
+        assert_eq!(value.as_json_value().unwrap(), serde_json::json!([{"inner": [1, 2]}, []]));
+    }
+
+    #[test]
+    fn test_as_char() {
+        assert_eq!(DynVal::from("x").as_char().unwrap(), 'x');
+        assert_eq!(DynVal::from("ä").as_char().unwrap(), 'ä');
+        assert!(DynVal::from("").as_char().is_err());
+        let err = DynVal::from("ab").at(Span(2, 4, 0)).as_char().unwrap_err();
+        assert_eq!(err.span(), Some(Span(2, 4, 0)));
+    }
+
+    #[test]
+    fn test_equality_ignores_span() {
+        let a = DynVal::from("hi").at(Span(0, 2, 0));
+        let b = DynVal::from("hi").at(Span(5, 7, 1));
+        assert!(a.value_eq(&b));
+        assert_eq!(a, b);
+        assert_eq!(DynVal::from("1").at(Span(0, 1, 0)), DynVal::from("1.0"));
+        assert!(!DynVal::from("1").value_eq(&DynVal::from("1.0")));
+        assert!(!a.value_eq(&DynVal::from("ho")));
+    }
+
+    #[test]
+    fn test_invalid_json_error_position() {
+        let err = DynVal::from("{\"a\": 1,\n \"b\" 2}").as_json_value().unwrap_err();
+        assert!(err.to_string().contains("line 2 column 6"), "{}", err);
+    }
+
+    #[test]
+    fn test_map_string() {
+        let value = DynVal::from("hello").at(Span(1, 6, 0)).map_string(|s| s.to_uppercase()).unwrap();
+        assert_eq!(value.0, "HELLO");
+        assert_eq!(value.span(), Some(Span(1, 6, 0)));
+    }
+
+    #[test]
+    fn test_debug_format() {
+        let value = DynVal::from("hello").at(Span(1, 6, 0));
+        assert_eq!(format!("{:?}", value), r#"DynVal("hello" @ 1..6)"#);
+        assert_eq!(format!("{}", value), "hello");
+        assert_eq!(format!("{:?}", DynVal::from("say \"hi\"")), r#"DynVal("say \"hi\"")"#);
+    }
+
+    #[test]
+    fn test_arithmetic_ops() {
+        let eval = |s: &str| crate::parse_string(0, s).unwrap().eval_no_vars().unwrap();
+        assert_eq!((DynVal::from(1) + DynVal::from(2)).unwrap(), eval("1 + 2"));
+        assert_eq!((DynVal::from(1) + DynVal::from(2)).unwrap().0, "3");
+        assert_eq!((DynVal::from(0.1) + DynVal::from(0.2)).unwrap().0, eval("0.1 + 0.2").0);
+        assert_eq!((DynVal::from("a") + DynVal::from(1)).unwrap().0, eval("\"a\" + 1").0);
+        assert_eq!((DynVal::from("a") + DynVal::from(1)).unwrap().0, "a1");
+        assert_eq!((DynVal::from(5) - DynVal::from(7.5)).unwrap().0, eval("5 - 7.5").0);
+        assert_eq!((DynVal::from(4) * DynVal::from("2.5")).unwrap().0, eval("4 * 2.5").0);
+        assert!((DynVal::from(1) + DynVal::from("a")).is_err());
+        assert!((DynVal::from("a") - DynVal::from(1)).is_err());
+        assert!((DynVal::from("a") * DynVal::from(1)).is_err());
+    }
+
+    #[test]
+    fn test_float_display() {
+        assert_eq!(DynVal::from(0.1 + 0.2).0, "0.3");
+        assert_eq!(DynVal::from(0.1 * 3.0).0, "0.3");
+        assert_eq!(DynVal::from(1.0 / 3.0).0, "0.3333333333333333");
+        assert_eq!(DynVal::from(1.0 / 7.0).0, "0.14285714285714285");
+        assert_eq!(DynVal::from(-3.7f64.fract()).0, "-0.7");
+        assert_eq!(DynVal::from(0.1234567890123456).0, "0.1234567890123456");
+        assert_eq!(DynVal::from(2.0).0, "2");
+        assert_eq!(DynVal::from(-1234.5678).0, "-1234.5678");
+        assert_eq!(DynVal::from(1e-7).0, "0.0000001");
+        assert_eq!(DynVal::from(-0.0).0, "-0");
+        assert_eq!(DynVal::from(999_999_999_999_999.0).0, "999999999999999");
+        assert_eq!(DynVal::from(1_234_567_890_123_456.0).0, "1234567890123456");
+        assert_eq!(DynVal::from(9_007_199_254_740_992.0).0, "9007199254740992");
+    }
+
+    #[test]
+    fn test_from_large_integers() {
+        let value = DynVal::from(9_007_199_254_740_993_u64);
+        assert_eq!(value.0, "9007199254740993");
+        assert_eq!(value.read_as::<_, u64>().unwrap(), 9_007_199_254_740_993_u64);
+        assert_eq!(DynVal::from(-9_007_199_254_740_993_i64).0, "-9007199254740993");
+        assert_eq!(DynVal::from(usize::MAX).0, usize::MAX.to_string());
+    }
+
+    #[test]
+    fn test_as_i64() {
+        assert_eq!(DynVal::from("9007199254740993").as_i64().unwrap(), 9_007_199_254_740_993);
+        assert_eq!(DynVal::from(-5).as_i64().unwrap(), -5);
+        assert!(DynVal::from(1.5).as_i64().is_err());
+    }
+
+    #[test]
+    fn test_try_as_json_value() {
+        assert_eq!(DynVal::from(r#"{"a": [1]}"#).try_as_json_value(), Some(serde_json::json!({"a": [1]})));
+        assert_eq!(DynVal::from("12").try_as_json_value(), Some(serde_json::json!(12)));
+        assert_eq!(DynVal::from("[1, 2").try_as_json_value(), None);
+        assert_eq!(DynVal::from("hello").try_as_json_value(), None);
+    }
+
+    #[test]
+    fn test_coerce_like() {
+        let (one, one_float, word) = (DynVal::from("1"), DynVal::from("1.0"), DynVal::from("a"));
+        assert_eq!(one.coerce_like(&one_float), Comparable::Number(1.0));
+        assert_eq!(one.coerce_like(&word), Comparable::String("1"));
+        assert_eq!(word.coerce_like(&one), Comparable::String("a"));
+    }
+
+    #[test]
+    fn test_as_percentage() {
+        assert_eq!(DynVal::from("50%").as_percentage().unwrap(), 0.5);
+        assert_eq!(DynVal::from("-12.5%").as_percentage().unwrap(), -0.125);
+        assert_eq!(DynVal::from("0.5").as_percentage().unwrap(), 0.5);
+        assert!(DynVal::from("%").as_percentage().is_err());
+        assert!(DynVal::from("half").as_percentage().is_err());
+        assert!(DynVal::from("50%%").as_percentage().is_err());
+    }
+
+    #[test]
+    fn test_from_bool() {
+        assert_eq!(DynVal::from_bool(true).0, "true");
+        assert_eq!(DynVal::from_bool(false).0, "false");
+        assert_eq!(DynVal::from_bool(true), DynVal::from("true"));
+        assert!(DynVal::from_bool(true).as_bool().unwrap());
+        assert!(!DynVal::from_bool(false).as_bool().unwrap());
+    }
+
+    #[test]
+    fn test_as_str() {
+        for value in [DynVal::from("hi"), DynVal::from(1.5), DynVal::empty(), DynVal::from(r#"{"a": 1}"#)].iter() {
+            assert_eq!(value.as_str(), value.as_string().unwrap());
+        }
+    }
+
     // use pretty_assertions::assert_eq;
     //#[test]
     // fn test_parse_vec() {
@@ -43,6 +43,42 @@ impl Error {
     }
 }
 
+/// A parse error in a form suitable for displaying it to the user, i.e. by underlining the offending part of the input.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error("{message}")]
+pub struct ParseError {
+    pub span: Span,
+    pub message: String,
+    /// The tokens the parser would have accepted at this position, if known.
+    pub expected: Vec<String>,
+}
+
+impl ParseError {
+    pub fn from_lalrpop(
+        file_id: usize,
+        src: &str,
+        err: lalrpop_util::ParseError<usize, lexer::Token, lexer::LexicalError>,
+    ) -> Self {
+        use lalrpop_util::ParseError::*;
+        let span = get_parse_error_span(file_id, &err).unwrap_or(Span(0, 0, file_id));
+        let text = src.get(span.0..span.1).unwrap_or_default();
+        let (message, expected) = match err {
+            InvalidToken { .. } => ("Invalid token".to_string(), Vec::new()),
+            UnrecognizedEOF { expected, .. } => ("Unexpected end of input".to_string(), expected),
+            // the parser reports no expected tokens if only the end of input would have been valid.
+            UnrecognizedToken { expected, .. } if expected.is_empty() => {
+                (format!("Unexpected trailing input `{}`", text), expected)
+            }
+            UnrecognizedToken { expected, .. } => (format!("Unexpected token `{}`", text), expected),
+            ExtraToken { .. } => (format!("Unexpected trailing input `{}`", text), Vec::new()),
+            User { .. } if text.starts_with('"') => ("Unterminated string literal".to_string(), Vec::new()),
+            User { .. } => (format!("Invalid input `{}`", text), Vec::new()),
+        };
+        let expected = expected.into_iter().map(|x| x.trim_matches('"').to_string()).collect();
+        ParseError { span, message, expected }
+    }
+}
+
 fn get_parse_error_span(
     file_id: usize,
     err: &lalrpop_util::ParseError<usize, lexer::Token, lexer::LexicalError>,
@@ -1,12 +1,12 @@
 use itertools::Itertools;
 
 use crate::{
-    ast::{BinOp, SimplExpr, Span, UnaryOp},
+    ast::{BinOp, SimplExpr, Span, UnaryOp, VarName},
     dynval::{ConversionError, DynVal},
 };
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
-#[derive(Debug, thiserror::Error)]
+#[derive(Debug, thiserror::Error, strum::IntoStaticStr)]
 pub enum EvalError {
     #[error("Tried to reference variable `{0}`, but we cannot access variables here")]
     NoVariablesAllowed(String),
@@ -15,7 +15,7 @@ pub enum EvalError {
     InvalidRegex(#[from] regex::Error),
 
     #[error("got unresolved variable `{0}`")]
-    UnresolvedVariable(VarName),
+    UnresolvedVariable(String),
 
     #[error("Type error: {0}")]
     ConversionError(#[from] ConversionError),
@@ -23,8 +23,14 @@ pub enum EvalError {
     #[error("Incorrect number of arguments given to function: {0}")]
     WrongArgCount(String),
 
-    #[error("Unknown function {0}")]
-    UnknownFunction(String),
+    #[error("Argument {1} given to function {0} is not a {2}")]
+    WrongArgType(String, usize, &'static str),
+
+    #[error("Element {1} of the array given to function {0} is not a {2}")]
+    WrongElementType(String, usize, &'static str),
+
+    #[error("Unknown function {0}{}", fmt_suggestion(.1))]
+    UnknownFunction(String, Option<String>),
 
     #[error("Unknown variable {0}")]
     UnknownVariable(String),
@@ -32,6 +38,30 @@ pub enum EvalError {
     #[error("Unable to index into value {0}")]
     CannotIndex(String),
 
+    #[error("Index {0} is out of range for an array of length {1}")]
+    IndexOutOfRange(i32, usize),
+
+    #[error("Error in sub-expression `{0}`: {1}")]
+    SubExpression(String, Box<crate::error::Error>),
+
+    #[error("Division by zero")]
+    DivisionByZero,
+
+    #[error("Arithmetic resulted in a non-finite number: {0}")]
+    NonFiniteResult(f64),
+
+    #[error("Integer overflow in {0}")]
+    Overflow(String),
+
+    #[error("Invalid time format: {0}")]
+    InvalidTimeFormat(String),
+
+    #[error("Invalid glob pattern `{0}`: {1}")]
+    InvalidGlob(String, String),
+
+    #[error("Invalid base64 `{0}`: {1}")]
+    InvalidBase64(String, String),
+
     #[error("{1}")]
     Spanned(Span, Box<EvalError>),
 }
@@ -45,18 +75,206 @@ impl EvalError {
         }
     }
 
+    /// Attach the given span to this error. If the error already has a span, that innermost, most specific span is kept,
+    /// rather than wrapping the error a second time.
     pub fn at(self, span: Span) -> Self {
-        Self::Spanned(span, Box::new(self))
+        match self {
+            EvalError::Spanned(..) => self,
+            _ => Self::Spanned(span, Box::new(self)),
+        }
+    }
+
+    /// Whether this error was caused by a mistake in the expression itself, and should thus be shown to the user.
+    /// Returns false for errors that indicate a problem in the host application, like a function that was never registered.
+    pub fn is_user_error(&self) -> bool {
+        match self {
+            EvalError::NoVariablesAllowed(_)
+            | EvalError::InvalidRegex(_)
+            | EvalError::UnresolvedVariable(_)
+            | EvalError::ConversionError(_)
+            | EvalError::WrongArgCount(_)
+            | EvalError::WrongArgType(..)
+            | EvalError::WrongElementType(..)
+            | EvalError::UnknownVariable(_)
+            | EvalError::CannotIndex(_)
+            | EvalError::IndexOutOfRange(..)
+            | EvalError::SubExpression(..)
+            | EvalError::DivisionByZero
+            | EvalError::NonFiniteResult(_)
+            | EvalError::Overflow(_)
+            | EvalError::InvalidTimeFormat(_)
+            | EvalError::InvalidGlob(..)
+            | EvalError::InvalidBase64(..) => true,
+            EvalError::UnknownFunction(..) => false,
+            EvalError::Spanned(_, err) => err.is_user_error(),
+        }
     }
+
+    /// Convert this error into a JSON object like `{"kind": "DivisionByZero", "message": "Division by zero", "span": [4, 9]}`,
+    /// for reporting it to a frontend. The kind is the name of the underlying error, and the span is `null` if unknown.
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut inner = self;
+        while let EvalError::Spanned(_, err) = inner {
+            inner = err;
+        }
+        let kind: &'static str = inner.into();
+        serde_json::json!({
+            "kind": kind,
+            "message": self.to_string(),
+            "span": self.span().map(|span| [span.0, span.1]),
+        })
+    }
+}
+
+fn fmt_suggestion(suggestion: &Option<String>) -> String {
+    suggestion.as_ref().map(|x| format!(", did you mean {}?", x)).unwrap_or_default()
+}
+
+type DebugCallback<'a> = dyn Fn(&str, &DynVal) + 'a;
+
+/// Options changing how an expression is evaluated, passed to [`SimplExpr::eval_with_options`].
+/// The default options match the behaviour of [`SimplExpr::eval`].
+#[derive(Clone, Copy, Default)]
+pub struct EvalOptions<'a> {
+    lenient_indexing: bool,
+    strict_indexing: bool,
+    registry: Option<&'a FunctionRegistry>,
+    debug_callback: Option<&'a DebugCallback<'a>>,
+    try_depth: usize,
 }
 
-type VarName = String;
+impl std::fmt::Debug for EvalOptions<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EvalOptions")
+            .field("lenient_indexing", &self.lenient_indexing)
+            .field("strict_indexing", &self.strict_indexing)
+            .field("registry", &self.registry)
+            .field("debug_callback", &self.debug_callback.map(|_| "<callback>"))
+            .field("try_depth", &self.try_depth)
+            .finish()
+    }
+}
+
+impl<'a> EvalOptions<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Make indexing into a value that is not a json array or object result in an empty value, rather than an error.
+    pub fn with_lenient_indexing(mut self, lenient_indexing: bool) -> Self {
+        self.lenient_indexing = lenient_indexing;
+        self
+    }
+
+    /// Make indexing into a json array with an index outside of its bounds result in an error, rather than null.
+    pub fn with_strict_indexing(mut self, strict_indexing: bool) -> Self {
+        self.strict_indexing = strict_indexing;
+        self
+    }
+
+    /// Look up called functions in the given registry before falling back to the builtins.
+    pub fn with_registry(mut self, registry: &'a FunctionRegistry) -> Self {
+        self.registry = Some(registry);
+        self
+    }
+
+    /// Call the given function with the label and value whenever `debug(value, label)` is evaluated.
+    pub fn with_debug_callback(mut self, callback: &'a DebugCallback<'a>) -> Self {
+        self.debug_callback = Some(callback);
+        self
+    }
+}
 
 pub trait FunctionSource {
     type Err;
     fn run_fn(&self, name: &str, args: &[DynVal]) -> Result<DynVal, Self::Err>;
 }
 
+type RegisteredFn = Box<dyn Fn(&[DynVal]) -> Result<DynVal, EvalError>>;
+
+/// A set of user-provided functions that can be called from within an expression.
+/// Functions that are not registered here fall back to the builtin functions.
+#[derive(Default)]
+pub struct FunctionRegistry {
+    functions: HashMap<String, RegisteredFn>,
+}
+
+impl std::fmt::Debug for FunctionRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_set().entries(self.functions.keys()).finish()
+    }
+}
+
+impl FunctionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a function under the given name, replacing any function previously registered under that name.
+    /// Registered functions take priority over builtins of the same name.
+    pub fn register(&mut self, name: impl Into<String>, f: impl Fn(&[DynVal]) -> Result<DynVal, EvalError> + 'static) {
+        self.functions.insert(name.into(), Box::new(f));
+    }
+
+    /// Call the function with the given name, falling back to the builtins, which are evaluated with the given options.
+    pub(crate) fn call(&self, name: &str, args: &[DynVal], options: &EvalOptions) -> Result<DynVal, EvalError> {
+        match self.functions.get(name) {
+            Some(f) => f(args),
+            None => call_expr_function(name, args.to_vec(), options).map_err(|err| match err {
+                EvalError::UnknownFunction(name, _) => {
+                    let candidates = BUILTIN_FUNCTIONS.iter().copied().chain(self.functions.keys().map(|x| x.as_str()));
+                    let suggestion = closest_match(&name, candidates);
+                    EvalError::UnknownFunction(name, suggestion)
+                }
+                err => err,
+            }),
+        }
+    }
+}
+
+impl FunctionSource for FunctionRegistry {
+    type Err = EvalError;
+
+    fn run_fn(&self, name: &str, args: &[DynVal]) -> Result<DynVal, Self::Err> {
+        self.call(name, args, &EvalOptions::new().with_registry(self))
+    }
+}
+
+/// The type an expression is statically inferred to evaluate to, as returned by [`SimplExpr::type_hint`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SimplType {
+    Number,
+    String,
+    Bool,
+    Json,
+    /// The type depends on the values the expression is evaluated with.
+    Unknown,
+}
+
+impl SimplType {
+    fn of_literal(value: &DynVal) -> Self {
+        let s = value.as_str();
+        if s == "true" || s == "false" {
+            SimplType::Bool
+        } else if s.parse::<f64>().map(f64::is_finite).unwrap_or(false) {
+            SimplType::Number
+        } else if (s.starts_with('[') || s.starts_with('{')) && value.try_as_json_value().is_some() {
+            SimplType::Json
+        } else {
+            SimplType::String
+        }
+    }
+
+    /// The type of a value that is one of two branches, which is only known if both agree.
+    fn either(a: Self, b: Self) -> Self {
+        if a == b {
+            a
+        } else {
+            SimplType::Unknown
+        }
+    }
+}
+
 impl SimplExpr {
     pub fn map_terminals_into(self, f: impl Fn(Self) -> Self) -> Self {
         use SimplExpr::*;
@@ -65,13 +283,14 @@ impl SimplExpr {
             UnaryOp(span, op, box a) => UnaryOp(span, op, box f(a)),
             IfElse(span, box a, box b, box c) => IfElse(span, box f(a), box f(b), box f(c)),
             JsonAccess(span, box a, box b) => JsonAccess(span, box f(a), box f(b)),
+            SafeAccess(span, box a, box b) => SafeAccess(span, box f(a), box f(b)),
             FunctionCall(span, name, args) => FunctionCall(span, name, args.into_iter().map(f).collect()),
             other => f(other),
         }
     }
 
     /// resolve variable references in the expression. Fails if a variable cannot be resolved.
-    pub fn resolve_refs(self, variables: &HashMap<VarName, DynVal>) -> Result<Self, EvalError> {
+    pub fn resolve_refs(self, variables: &HashMap<String, DynVal>) -> Result<Self, EvalError> {
         use SimplExpr::*;
         match self {
             // Literal(x) => Ok(Literal(AttrValue::from_primitive(x.resolve_fully(&variables)?))),
@@ -84,24 +303,104 @@ impl SimplExpr {
             JsonAccess(span, box a, box b) => {
                 Ok(JsonAccess(span, box a.resolve_refs(variables)?, box b.resolve_refs(variables)?))
             }
+            SafeAccess(span, box a, box b) => {
+                Ok(SafeAccess(span, box a.resolve_refs(variables)?, box b.resolve_refs(variables)?))
+            }
             FunctionCall(span, function_name, args) => Ok(FunctionCall(
                 span,
                 function_name,
                 args.into_iter().map(|a| a.resolve_refs(variables)).collect::<Result<_, EvalError>>()?,
             )),
-            VarRef(span, ref name) => match variables.get(name) {
+            VarRef(span, ref name) => match variables.get(&**name) {
                 Some(value) => Ok(Literal(span, value.clone())),
                 None => Err(EvalError::UnknownVariable(name.to_string()).at(span)),
             },
         }
     }
 
-    pub fn var_refs(&self) -> Vec<&String> {
+    /// Replace calls to the function `name` with the given body, in which references to the parameters are substituted by
+    /// the arguments of the call. Calls with a number of arguments not matching the parameters are left untouched.
+    pub fn inline_function(self, name: &str, params: &[String], body: &SimplExpr) -> Self {
+        use SimplExpr::*;
+        match self {
+            FunctionCall(span, function_name, args) => {
+                let args = args.into_iter().map(|a| a.inline_function(name, params, body)).collect_vec();
+                if function_name == name && args.len() == params.len() {
+                    let bindings: HashMap<&str, SimplExpr> = params.iter().map(|p| p.as_str()).zip(args).collect();
+                    body.clone().substitute_vars(&bindings)
+                } else {
+                    FunctionCall(span, function_name, args)
+                }
+            }
+            Literal(..) | VarRef(..) => self,
+            other => other.map_terminals_into(|x| x.inline_function(name, params, body)),
+        }
+    }
+
+    /// Replace all references to the variable `name` with the given value, leaving references to other variables untouched.
+    pub fn bind(self, name: &str, value: DynVal) -> Self {
+        match self {
+            SimplExpr::VarRef(span, var_name) if &*var_name == name => SimplExpr::Literal(span, value.at(span)),
+            SimplExpr::Literal(..) | SimplExpr::VarRef(..) => self,
+            other => other.map_terminals_into(|x| x.bind(name, value.clone())),
+        }
+    }
+
+    fn substitute_vars(self, bindings: &HashMap<&str, SimplExpr>) -> Self {
+        match self {
+            SimplExpr::VarRef(span, name) => match bindings.get(&*name) {
+                Some(value) => value.clone(),
+                None => SimplExpr::VarRef(span, name),
+            },
+            SimplExpr::Literal(..) => self,
+            other => other.map_terminals_into(|x| x.substitute_vars(bindings)),
+        }
+    }
+
+    /// Names of all functions called in this expression.
+    pub fn free_functions(&self) -> Vec<&String> {
+        use SimplExpr::*;
+        match self {
+            Literal(..) | VarRef(..) => Vec::new(),
+            BinOp(_, box a, _, box b) | JsonAccess(_, box a, box b) | SafeAccess(_, box a, box b) => {
+                let mut functions = a.free_functions();
+                functions.append(&mut b.free_functions());
+                functions
+            }
+            UnaryOp(_, _, box x) => x.free_functions(),
+            IfElse(_, box a, box b, box c) => {
+                let mut functions = a.free_functions();
+                functions.append(&mut b.free_functions());
+                functions.append(&mut c.free_functions());
+                functions
+            }
+            FunctionCall(_, name, args) => {
+                std::iter::once(name).chain(args.iter().flat_map(|a| a.free_functions())).collect_vec()
+            }
+        }
+    }
+
+    /// Whether the function `name` is called anywhere in this expression. Unlike [`SimplExpr::free_functions`],
+    /// this stops at the first call found and does not allocate.
+    pub fn contains_function(&self, name: &str) -> bool {
+        use SimplExpr::*;
+        match self {
+            Literal(..) | VarRef(..) => false,
+            BinOp(_, box a, _, box b) | JsonAccess(_, box a, box b) | SafeAccess(_, box a, box b) => {
+                a.contains_function(name) || b.contains_function(name)
+            }
+            UnaryOp(_, _, box x) => x.contains_function(name),
+            IfElse(_, box a, box b, box c) => a.contains_function(name) || b.contains_function(name) || c.contains_function(name),
+            FunctionCall(_, function_name, args) => function_name == name || args.iter().any(|a| a.contains_function(name)),
+        }
+    }
+
+    pub fn var_refs(&self) -> Vec<&VarName> {
         use SimplExpr::*;
         match self {
             Literal(..) => Vec::new(),
             VarRef(_, name) => vec![name],
-            BinOp(_, box a, _, box b) | JsonAccess(_, box a, box b) => {
+            BinOp(_, box a, _, box b) | JsonAccess(_, box a, box b) | SafeAccess(_, box a, box b) => {
                 let mut refs = a.var_refs();
                 refs.append(&mut b.var_refs());
                 refs
@@ -117,6 +416,73 @@ impl SimplExpr {
         }
     }
 
+    /// Collect the values of all literals in this expression, in source order.
+    /// This includes the keys of `.field` accesses, which the parser turns into literals.
+    pub fn literal_values(&self) -> Vec<&DynVal> {
+        use SimplExpr::*;
+        match self {
+            Literal(_, value) => vec![value],
+            VarRef(..) => Vec::new(),
+            BinOp(_, box a, _, box b) | JsonAccess(_, box a, box b) | SafeAccess(_, box a, box b) => {
+                let mut values = a.literal_values();
+                values.append(&mut b.literal_values());
+                values
+            }
+            UnaryOp(_, _, box x) => x.literal_values(),
+            IfElse(_, box a, box b, box c) => {
+                let mut values = a.literal_values();
+                values.append(&mut b.literal_values());
+                values.append(&mut c.literal_values());
+                values
+            }
+            FunctionCall(_, _, args) => args.iter().flat_map(|a| a.literal_values()).collect_vec(),
+        }
+    }
+
+    /// Infer the type this expression evaluates to without evaluating it, on a best-effort basis.
+    /// Returns `None` if the expression calls a function that is not a builtin, as its return type cannot be known.
+    pub fn type_hint(&self) -> Option<SimplType> {
+        use SimplExpr::*;
+        Some(match self {
+            Literal(_, value) => SimplType::of_literal(value),
+            VarRef(..) => SimplType::Unknown,
+            BinOp(_, a, op, b) => {
+                use crate::ast::BinOp as Op;
+                let (a, b) = (a.type_hint()?, b.type_hint()?);
+                match op {
+                    Op::Minus | Op::Times | Op::Div | Op::Mod => SimplType::Number,
+                    Op::Equals | Op::NotEquals | Op::And | Op::Or | Op::GT | Op::LT | Op::RegexMatch => SimplType::Bool,
+                    // `+` adds if the left side is a number, and concatenates otherwise.
+                    Op::Plus => match (a, b) {
+                        (SimplType::Number, SimplType::Number) => SimplType::Number,
+                        (SimplType::String | SimplType::Bool | SimplType::Json, _) => SimplType::String,
+                        _ => SimplType::Unknown,
+                    },
+                    Op::Elvis => SimplType::either(a, b),
+                }
+            }
+            UnaryOp(_, _, x) => {
+                x.type_hint()?;
+                SimplType::Bool
+            }
+            IfElse(_, cond, yes, no) => {
+                cond.type_hint()?;
+                SimplType::either(yes.type_hint()?, no.type_hint()?)
+            }
+            JsonAccess(_, value, index) | SafeAccess(_, value, index) => {
+                value.type_hint()?;
+                index.type_hint()?;
+                SimplType::Unknown
+            }
+            FunctionCall(_, name, args) => {
+                for arg in args {
+                    arg.type_hint()?;
+                }
+                builtin_return_type(name)?
+            }
+        })
+    }
+
     pub fn eval_no_vars(&self) -> Result<DynVal, EvalError> {
         match self.eval(&HashMap::new()) {
             Ok(x) => Ok(x),
@@ -125,87 +491,365 @@ impl SimplExpr {
         }
     }
 
-    pub fn eval(&self, values: &HashMap<VarName, DynVal>) -> Result<DynVal, EvalError> {
+    pub fn eval(&self, values: &HashMap<String, DynVal>) -> Result<DynVal, EvalError> {
+        self.eval_with_options(values, &EvalOptions::default())
+    }
+
+    pub fn eval_to_bool(&self, values: &HashMap<String, DynVal>) -> Result<bool, EvalError> {
+        Ok(self.eval(values)?.as_bool()?)
+    }
+
+    /// Evaluate this expression as a boolean. `&&`, `||`, `!` and comparisons are evaluated directly on booleans
+    /// without building intermediate values. Unlike [`SimplExpr::eval`], `&&` and `||` short-circuit,
+    /// so an error in an operand that is not needed for the result is not reported.
+    pub fn eval_bool(&self, values: &HashMap<String, DynVal>) -> Result<bool, EvalError> {
+        match self {
+            SimplExpr::Literal(_, x) => Ok(x.as_bool()?),
+            SimplExpr::UnaryOp(_, UnaryOp::Not, a) => Ok(!a.eval_bool(values)?),
+            SimplExpr::BinOp(_, a, BinOp::And, b) => Ok(a.eval_bool(values)? && b.eval_bool(values)?),
+            SimplExpr::BinOp(_, a, BinOp::Or, b) => Ok(a.eval_bool(values)? || b.eval_bool(values)?),
+            SimplExpr::BinOp(_, a, op @ (BinOp::Equals | BinOp::NotEquals | BinOp::GT | BinOp::LT), b) => {
+                let (a, b) = (a.eval(values)?, b.eval(values)?);
+                Ok(match op {
+                    BinOp::Equals => a == b,
+                    BinOp::NotEquals => a != b,
+                    BinOp::GT => a.coerce_like(&b) > b.coerce_like(&a),
+                    _ => a.coerce_like(&b) < b.coerce_like(&a),
+                })
+            }
+            _ => self.eval_to_bool(values),
+        }
+    }
+
+    pub fn eval_to_f64(&self, values: &HashMap<String, DynVal>) -> Result<f64, EvalError> {
+        Ok(self.eval(values)?.as_f64()?)
+    }
+
+    pub fn eval_to_string(&self, values: &HashMap<String, DynVal>) -> Result<String, EvalError> {
+        Ok(self.eval(values)?.as_string()?)
+    }
+
+    /// Evaluate the expression, returning the given default if evaluation fails.
+    pub fn eval_with_default(&self, values: &HashMap<String, DynVal>, default: DynVal) -> DynVal {
+        self.eval(values).unwrap_or(default)
+    }
+
+    /// Evaluate the expression, computing a fallback value from the error if evaluation fails.
+    /// This is useful to log the error before falling back to a default.
+    pub fn eval_or_else(&self, values: &HashMap<String, DynVal>, f: impl FnOnce(EvalError) -> DynVal) -> DynVal {
+        self.eval(values).unwrap_or_else(f)
+    }
+
+    /// Evaluate the expression, looking up called functions in the given registry before falling back to the builtins.
+    pub fn eval_with_registry(
+        &self,
+        values: &HashMap<String, DynVal>,
+        registry: &FunctionRegistry,
+    ) -> Result<DynVal, EvalError> {
+        self.eval_with_options(values, &EvalOptions::default().with_registry(registry))
+    }
+
+    pub fn eval_with_options(&self, values: &HashMap<String, DynVal>, options: &EvalOptions) -> Result<DynVal, EvalError> {
         let span = self.span();
         let value = match self {
             SimplExpr::Literal(_, x) => Ok(x.clone()),
             SimplExpr::VarRef(span, ref name) => {
-                Ok(values.get(name).cloned().ok_or_else(|| EvalError::UnresolvedVariable(name.to_string()).at(*span))?.at(*span))
+                let value = values.get(&**name).ok_or_else(|| EvalError::UnresolvedVariable(name.to_string()).at(*span))?;
+                Ok(value.clone().at(*span))
             }
             SimplExpr::BinOp(_, a, op, b) => {
-                let a = a.eval(values)?;
-                let b = b.eval(values)?;
-                Ok(match op {
-                    BinOp::Equals => DynVal::from(a == b),
-                    BinOp::NotEquals => DynVal::from(a != b),
-                    BinOp::And => DynVal::from(a.as_bool()? && b.as_bool()?),
-                    BinOp::Or => DynVal::from(a.as_bool()? || b.as_bool()?),
-                    BinOp::Plus => match a.as_f64() {
-                        Ok(num) => DynVal::from(num + b.as_f64()?),
-                        Err(_) => DynVal::from(format!("{}{}", a.as_string()?, b.as_string()?)),
-                    },
-                    BinOp::Minus => DynVal::from(a.as_f64()? - b.as_f64()?),
-                    BinOp::Times => DynVal::from(a.as_f64()? * b.as_f64()?),
-                    BinOp::Div => DynVal::from(a.as_f64()? / b.as_f64()?),
-                    BinOp::Mod => DynVal::from(a.as_f64()? % b.as_f64()?),
-                    BinOp::GT => DynVal::from(a.as_f64()? > b.as_f64()?),
-                    BinOp::LT => DynVal::from(a.as_f64()? < b.as_f64()?),
-                    #[allow(clippy::useless_conversion)]
-                    BinOp::Elvis => DynVal::from(if a.0.is_empty() { b } else { a }),
-                    BinOp::RegexMatch => {
-                        let regex = regex::Regex::new(&b.as_string()?)?;
-                        DynVal::from(regex.is_match(&a.as_string()?))
-                    }
-                })
+                let a = a.eval_with_options(values, options)?;
+                let b = b.eval_with_options(values, options)?;
+                eval_bin_op(a, op, b)
             }
             SimplExpr::UnaryOp(_, op, a) => {
-                let a = a.eval(values)?;
-                Ok(match op {
-                    UnaryOp::Not => DynVal::from(!a.as_bool()?),
-                })
+                let a = a.eval_with_options(values, options)?;
+                eval_unary_op(op, a)
             }
             SimplExpr::IfElse(_, cond, yes, no) => {
-                if cond.eval(values)?.as_bool()? {
-                    yes.eval(values)
+                if cond.eval_with_options(values, options)?.as_bool()? {
+                    yes.eval_with_options(values, options)
                 } else {
-                    no.eval(values)
+                    no.eval_with_options(values, options)
                 }
             }
             SimplExpr::JsonAccess(span, val, index) => {
-                let val = val.eval(values)?;
-                let index = index.eval(values)?;
-                match val.as_json_value()? {
-                    serde_json::Value::Array(val) => {
-                        let index = index.as_i32()?;
-                        let indexed_value = val.get(index as usize).unwrap_or(&serde_json::Value::Null);
-                        Ok(DynVal::from(indexed_value))
-                    }
-                    serde_json::Value::Object(val) => {
-                        let indexed_value = val
-                            .get(&index.as_string()?)
-                            .or_else(|| val.get(&index.as_i32().ok()?.to_string()))
-                            .unwrap_or(&serde_json::Value::Null);
-                        Ok(DynVal::from(indexed_value))
-                    }
-                    _ => Err(EvalError::CannotIndex(format!("{}", val)).at(*span)),
-                }
+                let val = val.eval_with_options(values, options)?;
+                let index = index.eval_with_options(values, options)?;
+                eval_indexing(*span, val, index, options)
+            }
+            SimplExpr::SafeAccess(_, val, index) => {
+                let val = val.eval_with_options(values, options)?;
+                let index = index.eval_with_options(values, options)?;
+                Ok(eval_safe_access(val, index))
             }
             SimplExpr::FunctionCall(span, function_name, args) => {
-                let args = args.into_iter().map(|a| a.eval(values)).collect::<Result<_, EvalError>>()?;
-                call_expr_function(&function_name, args).map_err(|e| e.at(*span))
+                let args = args.iter().map(|a| a.eval_with_options(values, options)).collect::<Result<Vec<_>, EvalError>>()?;
+                eval_function_call(function_name, args, values, options).map_err(|e| e.at(*span))
             }
         };
         Ok(value?.at(span))
     }
 }
 
-fn call_expr_function(name: &str, args: Vec<DynVal>) -> Result<DynVal, EvalError> {
+pub(crate) fn eval_bin_op(a: DynVal, op: &BinOp, b: DynVal) -> Result<DynVal, EvalError> {
+    Ok(match op {
+        BinOp::Equals => DynVal::from(a == b),
+        BinOp::NotEquals => DynVal::from(a != b),
+        BinOp::And => DynVal::from(a.as_bool()? && b.as_bool()?),
+        BinOp::Or => DynVal::from(a.as_bool()? || b.as_bool()?),
+        BinOp::Plus => match a.as_f64() {
+            Ok(num) => DynVal::from(finite(num + b.as_f64()?)?),
+            Err(_) => DynVal::from(format!("{}{}", a.as_string()?, b.as_string()?)),
+        },
+        BinOp::Minus => DynVal::from(finite(a.as_f64()? - b.as_f64()?)?),
+        BinOp::Times => DynVal::from(finite(a.as_f64()? * b.as_f64()?)?),
+        BinOp::Div => DynVal::from(finite(a.as_f64()? / non_zero(b.as_f64()?)?)?),
+        BinOp::Mod => DynVal::from(finite(a.as_f64()? % non_zero(b.as_f64()?)?)?),
+        BinOp::GT => DynVal::from(a.coerce_like(&b) > b.coerce_like(&a)),
+        BinOp::LT => DynVal::from(a.coerce_like(&b) < b.coerce_like(&a)),
+        #[allow(clippy::useless_conversion)]
+        BinOp::Elvis => DynVal::from(if a.is_empty() { b } else { a }),
+        BinOp::RegexMatch => {
+            let regex = regex::Regex::new(&b.as_string()?)?;
+            DynVal::from(regex.is_match(&a.as_string()?))
+        }
+    })
+}
+
+/// Guard against arithmetic producing `NaN` or infinite values, which would otherwise silently propagate.
+fn finite(x: f64) -> Result<f64, EvalError> {
+    if x.is_finite() {
+        Ok(x)
+    } else {
+        Err(EvalError::NonFiniteResult(x))
+    }
+}
+
+fn non_zero(x: f64) -> Result<f64, EvalError> {
+    if x == 0.0 {
+        Err(EvalError::DivisionByZero)
+    } else {
+        Ok(x)
+    }
+}
+
+pub(crate) fn eval_unary_op(op: &UnaryOp, a: DynVal) -> Result<DynVal, EvalError> {
+    Ok(match op {
+        UnaryOp::Not => DynVal::from(!a.as_bool()?),
+    })
+}
+
+/// Index into `json`, the already parsed contents of `val`.
+/// Index into a value, as in `val[index]`, taking the lenient and strict indexing options into account.
+pub(crate) fn eval_indexing(span: Span, val: DynVal, index: DynVal, options: &EvalOptions) -> Result<DynVal, EvalError> {
+    let json = val.as_json_value();
+    let indexable = matches!(json, Ok(serde_json::Value::Array(_) | serde_json::Value::Object(_)));
+    if options.lenient_indexing && !indexable {
+        return Ok(DynVal::empty());
+    }
+    let json = json?;
+    if let (true, serde_json::Value::Array(arr)) = (options.strict_indexing, &json) {
+        let i = index.as_i32()?;
+        if i < 0 || i as usize >= arr.len() {
+            return Err(EvalError::IndexOutOfRange(i, arr.len()).at(span));
+        }
+    }
+    eval_json_access(span, &val, json, index)
+}
+
+/// Call a function with already evaluated arguments, using the registry and debug callback from the options.
+/// `values` are the variables available to the expression given to `try`.
+pub(crate) fn eval_function_call(
+    name: &str,
+    args: Vec<DynVal>,
+    values: &HashMap<String, DynVal>,
+    options: &EvalOptions,
+) -> Result<DynVal, EvalError> {
+    if let (Some(callback), "debug", [value, label]) = (options.debug_callback, name, args.as_slice()) {
+        callback(&label.as_string()?, value);
+    }
+    match options.registry {
+        _ if name == "try" => eval_try(&args, values, options),
+        Some(registry) => registry.call(name, &args, options),
+        None => call_expr_function(name, args, options),
+    }
+}
+
+pub(crate) fn eval_json_access(span: Span, val: &DynVal, json: serde_json::Value, index: DynVal) -> Result<DynVal, EvalError> {
+    match json {
+        serde_json::Value::Array(val) => {
+            let index = index.as_i32()?;
+            let indexed_value = val.get(index as usize).unwrap_or(&serde_json::Value::Null);
+            Ok(DynVal::from(indexed_value))
+        }
+        serde_json::Value::Object(val) => {
+            let indexed_value = val
+                .get(&index.as_string()?)
+                .or_else(|| val.get(&index.as_i32().ok()?.to_string()))
+                .unwrap_or(&serde_json::Value::Null);
+            Ok(DynVal::from(indexed_value))
+        }
+        _ => Err(EvalError::CannotIndex(format!("{}", val)).at(span)),
+    }
+}
+
+pub(crate) fn eval_safe_access(val: DynVal, index: DynVal) -> DynVal {
+    let indexed_value = match val.try_as_json_value() {
+        Some(serde_json::Value::Array(val)) => {
+            index.as_i32().ok().filter(|i| *i >= 0).and_then(|i| val.get(i as usize).cloned())
+        }
+        Some(serde_json::Value::Object(val)) => {
+            val.get(&index.0).or_else(|| val.get(&index.as_i32().ok()?.to_string())).cloned()
+        }
+        _ => None,
+    };
+    match indexed_value {
+        None | Some(serde_json::Value::Null) => DynVal::empty(),
+        Some(value) => DynVal::from(&value),
+    }
+}
+
+/// Functions whose result may differ between calls with the same arguments, as they are random or depend on outside state,
+/// or that have side effects, like `debug` calling the debug callback.
+/// Calls to these must never be evaluated ahead of time, e.g. during constant folding.
+pub(crate) const IMPURE_FUNCTIONS: &[&str] = &["random", "random_range", "try", "env", "debug"];
+
+/// How deeply calls to `try` may be nested, to prevent a variable containing a call to `try` on itself
+/// from recursing forever.
+const MAX_TRY_DEPTH: usize = 16;
+
+/// try(source, fallback): parse and evaluate `source` against the current variables,
+/// returning `fallback` if it fails to parse or evaluate.
+pub(crate) fn eval_try(args: &[DynVal], values: &HashMap<String, DynVal>, options: &EvalOptions) -> Result<DynVal, EvalError> {
+    match args {
+        [source, fallback] => {
+            if options.try_depth >= MAX_TRY_DEPTH {
+                return Ok(fallback.clone());
+            }
+            let options = EvalOptions { try_depth: options.try_depth + 1, ..*options };
+            match crate::parse_string(0, source.as_str()).map(|expr| expr.eval_with_options(values, &options)) {
+                Ok(Ok(value)) => Ok(value),
+                _ => Ok(fallback.clone()),
+            }
+        }
+        _ => Err(EvalError::WrongArgCount("try".to_string())),
+    }
+}
+
+/// Names of all builtin functions, used to suggest a function when an unknown one is called.
+const BUILTIN_FUNCTIONS: &[&str] = &[
+    "round", "to_fixed", "round_num", "round_to_multiple", "replace", "matches_any", "strip_ansi", "clamp01", "trunc",
+    "fract", "sign", "gcd", "lcm", "ceil_div", "version_compare", "as_fraction", "lerp", "to_json", "to_json_pretty",
+    "count_matches", "capitalize_locale", "rgb", "hsl", "rgb_to_hex", "hex_to_rgb", "rgb_to_hsl", "switch", "choose",
+    "to_hex", "to_binary", "to_octal", "from_base", "percent", "percent_of", "array_concat", "object_merge",
+    "object_merge_deep", "wrap_text", "slug", "replace_map", "dedent", "indent", "reverse", "unique", "slice",
+    "first_line", "last_line", "humanize_duration", "parse_duration", "debug", "sort_by_key", "min_by_key", "group_by",
+    "count", "sum", "product", "average", "try", "to_chars", "from_chars", "clamp_text_lines", "add_checked",
+    "mul_checked", "parse_number_with_unit", "escape_json", "unescape_json", "html_escape", "html_unescape",
+    "url_encode", "url_decode", "round_even", "take_while", "drop_while", "flatten", "flatten_deep", "pairs",
+    "object_from_pairs", "is_empty", "is_blank", "clamp_index", "wrap_index",
+    #[cfg(feature = "chrono")]
+    "format_time",
+    #[cfg(feature = "chrono")]
+    "format_time_utc",
+    #[cfg(feature = "glob")]
+    "matches_glob",
+    #[cfg(feature = "base64")]
+    "base64_encode",
+    #[cfg(feature = "base64")]
+    "base64_decode",
+    #[cfg(feature = "hashes")]
+    "hash_md5",
+    #[cfg(feature = "hashes")]
+    "hash_sha256",
+    #[cfg(feature = "rand")]
+    "random",
+    #[cfg(feature = "rand")]
+    "random_range",
+    #[cfg(feature = "env")]
+    "env",
+];
+
+/// The type returned by the builtin function `name`, or `None` if there is no such builtin.
+fn builtin_return_type(name: &str) -> Option<SimplType> {
+    if !BUILTIN_FUNCTIONS.contains(&name) {
+        return None;
+    }
+    Some(match name {
+        "round" | "to_fixed" | "round_num" | "round_even" | "round_to_multiple" | "clamp01" | "trunc" | "fract" | "sign"
+        | "gcd" | "lcm" | "add_checked" | "mul_checked" | "ceil_div" | "version_compare" | "lerp" | "count_matches"
+        | "from_base" | "percent" | "percent_of" | "parse_duration" | "count" | "sum" | "product" | "average" | "random"
+        | "random_range" | "clamp_index" | "wrap_index" | "as_fraction" => SimplType::Number,
+        "matches_any" | "matches_glob" | "is_empty" | "is_blank" => SimplType::Bool,
+        "hex_to_rgb" | "rgb_to_hsl" | "parse_number_with_unit" | "array_concat" | "object_merge" | "object_merge_deep"
+        | "unique" | "slice" | "sort_by_key" | "group_by" | "to_chars" | "take_while" | "drop_while" | "flatten"
+        | "flatten_deep" | "pairs" | "object_from_pairs" => SimplType::Json,
+        // these return one of their arguments, or a value of the same type as their argument.
+        "switch" | "choose" | "try" | "debug" | "reverse" | "min_by_key" => SimplType::Unknown,
+        _ => SimplType::String,
+    })
+}
+
+/// Find the candidate closest to `name` by edit distance, if any is close enough to likely be a typo of it.
+fn closest_match<'a>(name: &str, candidates: impl Iterator<Item = &'a str>) -> Option<String> {
+    let max_distance = (name.chars().count() / 3).max(1);
+    candidates
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate.to_string())
+}
+
+/// The levenshtein distance between the two strings, counted in chars.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect_vec();
+    let mut row = (0..=b.len()).collect_vec();
+    for (i, a) in a.chars().enumerate() {
+        let mut previous_diagonal = row[0];
+        row[0] = i + 1;
+        for (j, b) in b.iter().enumerate() {
+            let substitution = previous_diagonal + usize::from(a != *b);
+            previous_diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+pub(crate) fn call_expr_function(name: &str, args: Vec<DynVal>, options: &EvalOptions) -> Result<DynVal, EvalError> {
     match name {
-        "round" => match args.as_slice() {
+        // `round` and `to_fixed` produce a string with exactly the given amount of decimals, keeping trailing zeros
+        // (`round(1.5, 2)` is "1.50"). The result is still usable as a number in arithmetic and comparisons.
+        "round" | "to_fixed" => match args.as_slice() {
             [num, digits] => {
                 let num = num.as_f64()?;
                 let digits = digits.as_i32()?;
-                Ok(DynVal::from(format!("{:.1$}", num, digits as usize)))
+                Ok(DynVal::from(format!("{:.1$}", num, digits.max(0) as usize)))
+            }
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        // rounds to the given amount of decimals, but returns a plain number without trailing zeros (`round_num(1.5, 2)` is 1.5).
+        "round_num" => match args.as_slice() {
+            [num, digits] => {
+                let factor = 10f64.powi(digits.as_i32()?);
+                Ok(DynVal::from(finite((num.as_f64()? * factor).round() / factor)?))
+            }
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        // rounds exact halves to the nearest even digit (`round_even(2.5, 0)` is 2), where `round_num` rounds them
+        // away from zero (`round_num(2.5, 0)` is 3). Like `round_num`, the result is a plain number.
+        "round_even" => match args.as_slice() {
+            [num, digits] => {
+                let factor = 10f64.powi(digits.as_i32()?);
+                Ok(DynVal::from(finite(round_half_even(num.as_f64()? * factor) / factor + 0.0)?))
+            }
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        "round_to_multiple" => match args.as_slice() {
+            [num, multiple] => {
+                let multiple = non_zero(multiple.as_f64()?)?;
+                Ok(DynVal::from(finite((num.as_f64()? / multiple).round() * multiple)?))
             }
             _ => Err(EvalError::WrongArgCount(name.to_string())),
         },
@@ -218,6 +862,2165 @@ fn call_expr_function(name: &str, args: Vec<DynVal>) -> Result<DynVal, EvalError
             }
             _ => Err(EvalError::WrongArgCount(name.to_string())),
         },
-        _ => Err(EvalError::UnknownFunction(name.to_string())),
+        // patterns are compiled one by one, stopping at the first match.
+        "matches_any" => match args.as_slice() {
+            [string, patterns] => {
+                for (i, pattern) in json_array_arg(name, 1, patterns)?.iter().enumerate() {
+                    let pattern = pattern.as_str().ok_or_else(|| EvalError::WrongElementType(name.to_string(), i, "string"))?;
+                    if regex::Regex::new(pattern)?.is_match(string.as_str()) {
+                        return Ok(DynVal::from(true));
+                    }
+                }
+                Ok(DynVal::from(false))
+            }
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        // supports `*`, `?` and `[...]` character classes. `*` also matches path separators.
+        #[cfg(feature = "glob")]
+        "matches_glob" => match args.as_slice() {
+            [string, pattern] => {
+                let pattern = glob::Pattern::new(pattern.as_str())
+                    .map_err(|e| EvalError::InvalidGlob(pattern.as_string().unwrap_or_default(), e.msg.to_string()))?;
+                Ok(DynVal::from(pattern.matches(string.as_str())))
+            }
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        #[cfg(feature = "base64")]
+        "base64_encode" => match args.as_slice() {
+            [string] => Ok(DynVal::from(base64::encode(string.as_str()))),
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        #[cfg(feature = "base64")]
+        "base64_decode" => match args.as_slice() {
+            [string] => {
+                let bytes =
+                    base64::decode(string.as_str()).map_err(|e| EvalError::InvalidBase64(string.0.clone(), e.to_string()))?;
+                let decoded = String::from_utf8(bytes)
+                    .map_err(|e| ConversionError::new(string.clone(), "base64-encoded utf-8 string", e))?;
+                Ok(DynVal::from(decoded))
+            }
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        #[cfg(feature = "hashes")]
+        "hash_md5" => match args.as_slice() {
+            [string] => Ok(DynVal::from(format!("{:x}", md5::compute(string.as_str())))),
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        #[cfg(feature = "hashes")]
+        "hash_sha256" => match args.as_slice() {
+            [string] => {
+                use sha2::Digest;
+                Ok(DynVal::from(format!("{:x}", sha2::Sha256::digest(string.as_str().as_bytes()))))
+            }
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        "html_escape" => match args.as_slice() {
+            [string] => Ok(DynVal::from(html_escape(string.as_str()))),
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        // unknown or malformed entities are left untouched.
+        "html_unescape" => match args.as_slice() {
+            [string] => Ok(DynVal::from(html_unescape(string.as_str())?)),
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        "url_encode" => match args.as_slice() {
+            [string] => Ok(DynVal::from(url_encode(string.as_str()))),
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        // malformed percent sequences are an error rather than being passed through. `+` is not decoded to a space.
+        "url_decode" => match args.as_slice() {
+            [string] => Ok(DynVal::from(url_decode(string)?)),
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        // removes ANSI CSI escape sequences, such as colors (`\x1b[31m`) and cursor movement (`\x1b[2A`).
+        "strip_ansi" => match args.as_slice() {
+            [string] => {
+                let pattern = regex::Regex::new(r"\x1b\[[0-?]*[ -/]*[@-~]")?;
+                Ok(DynVal::from(pattern.replace_all(string.as_str(), "").into_owned()))
+            }
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        "clamp01" => match args.as_slice() {
+            [num] => Ok(DynVal::from(finite(num.as_f64()?.clamp(0.0, 1.0))?)),
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        // adding zero turns a negative zero result (as in `trunc(-0.5)`) into a plain 0.
+        "trunc" | "fract" => match args.as_slice() {
+            [num] => {
+                let num = num.as_f64()?;
+                Ok(DynVal::from(finite(if name == "trunc" { num.trunc() } else { num.fract() } + 0.0)?))
+            }
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        // unlike `f64::signum`, zero (including -0) results in 0.
+        "sign" => match args.as_slice() {
+            [num] => {
+                let num = num.as_f64()?;
+                Ok(DynVal::from(if num == 0.0 { 0 } else { num.signum() as i32 }))
+            }
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        // integer arithmetic that errors on overflow, rather than losing precision like the float based `+` and `*`.
+        "add_checked" | "mul_checked" => match args.as_slice() {
+            [a, b] => {
+                let (a, b) = (a.as_i64()?, b.as_i64()?);
+                let result = if name == "add_checked" { a.checked_add(b) } else { a.checked_mul(b) };
+                Ok(DynVal::from(result.ok_or_else(|| EvalError::Overflow(name.to_string()))?))
+            }
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        // both operate on the absolute values of their arguments. The lcm is computed in 128 bits, so it cannot overflow.
+        "gcd" | "lcm" => match args.as_slice() {
+            [a, b] => {
+                let (a, b) = (a.as_i64()?.unsigned_abs(), b.as_i64()?.unsigned_abs());
+                let gcd = gcd(a, b);
+                Ok(DynVal::from(match name {
+                    "gcd" => gcd.to_string(),
+                    _ if gcd == 0 => "0".to_string(),
+                    _ => (u128::from(a / gcd) * u128::from(b)).to_string(),
+                }))
+            }
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        // computed on integers rather than floats so the result is exact, using 128 bits to not overflow on `i64::MIN / -1`.
+        "ceil_div" => match args.as_slice() {
+            [a, b] => {
+                let (a, b) = (i128::from(a.as_i64()?), i128::from(b.as_i64()?));
+                if b == 0 {
+                    return Err(EvalError::DivisionByZero);
+                }
+                let rounds_up = a % b != 0 && (a % b > 0) == (b > 0);
+                Ok(DynVal::from((a / b + i128::from(rounds_up)).to_string()))
+            }
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        "version_compare" => match args.as_slice() {
+            [a, b] => Ok(DynVal::from(compare_versions(a.as_str(), b.as_str()) as i32)),
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        // splits a value like "12px" into {"value": 12, "unit": "px"}. Whitespace between the number and unit is ignored.
+        "parse_number_with_unit" => match args.as_slice() {
+            [string] => {
+                let pattern = regex::Regex::new(r"^\s*([+-]?(?:[0-9]+\.?[0-9]*|\.[0-9]+)(?:[eE][+-]?[0-9]+)?)\s*(.*?)\s*$")?;
+                let captures = pattern
+                    .captures(string.as_str())
+                    .ok_or_else(|| ConversionError { value: string.clone(), target_type: "number with unit", source: None })?;
+                let value = DynVal::from(captures[1].to_string()).as_f64()?;
+                let mut result = serde_json::Map::new();
+                result.insert("value".to_string(), DynVal::from(value).into_json_value());
+                result.insert("unit".to_string(), serde_json::Value::String(captures[2].to_string()));
+                Ok(DynVal::from(&serde_json::Value::Object(result)))
+            }
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        "as_fraction" => match args.as_slice() {
+            [value] => Ok(DynVal::from(finite(value.as_percentage()?)?)),
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        "lerp" => match args.as_slice() {
+            [a, b, t] => {
+                let a = a.as_f64()?;
+                let b = b.as_f64()?;
+                let t = t.as_f64()?;
+                Ok(DynVal::from(finite(a + (b - a) * t)?))
+            }
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        "to_json" => match args.as_slice() {
+            [value] => Ok(DynVal::from(format!("{}", value.clone().into_json_value()))),
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        "to_json_pretty" => match args.as_slice() {
+            [value] => Ok(DynVal::from(format!("{:#}", value.clone().into_json_value()))),
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        // both work on the contents of a JSON string literal, without the surrounding quotes.
+        "escape_json" => match args.as_slice() {
+            [string] => {
+                let escaped = serde_json::Value::String(string.as_string()?).to_string();
+                Ok(DynVal::from(escaped[1..escaped.len() - 1].to_string()))
+            }
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        "unescape_json" => match args.as_slice() {
+            [string] => serde_json::from_str::<String>(&format!("\"{}\"", string.as_str()))
+                .map(DynVal::from)
+                .map_err(|e| ConversionError::new(string.clone(), "escaped json string", e).into()),
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        // matches are non-overlapping, so `count_matches("aaaa", "aa")` gives 2, not 3.
+        "count_matches" => match args.as_slice() {
+            [string, pattern] => {
+                let string = string.as_string()?;
+                let pattern = regex::Regex::new(&pattern.as_string()?)?;
+                Ok(DynVal::from(pattern.find_iter(&string).count() as i32))
+            }
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        "capitalize_locale" => match args.as_slice() {
+            [string, locale] => Ok(DynVal::from(capitalize_locale(&string.as_string()?, &locale.as_string()?))),
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        "rgb" => match args.as_slice() {
+            [r, g, b] => Ok(DynVal::from(format_hex_color(r.as_f64()?, g.as_f64()?, b.as_f64()?))),
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        "hsl" => match args.as_slice() {
+            [h, s, l] => {
+                let (r, g, b) = hsl_to_rgb(h.as_f64()?, s.as_f64()?, l.as_f64()?);
+                Ok(DynVal::from(format_hex_color(r, g, b)))
+            }
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        // unlike `rgb`, channels outside of 0-255 are an error rather than being clamped.
+        "rgb_to_hex" => match args.as_slice() {
+            [r, g, b] => {
+                let channel = |x: &DynVal| match x.as_f64()? {
+                    value if (0.0..=255.0).contains(&value) => Ok(value),
+                    _ => Err(ConversionError { value: x.clone(), target_type: "color channel (0-255)", source: None }),
+                };
+                Ok(DynVal::from(format_hex_color(channel(r)?, channel(g)?, channel(b)?)))
+            }
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        "hex_to_rgb" => match args.as_slice() {
+            [color] => {
+                let (r, g, b) = parse_hex_color(color)?;
+                Ok(DynVal::from(vec![DynVal::from(r), DynVal::from(g), DynVal::from(b)]))
+            }
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        "rgb_to_hsl" => match args.as_slice() {
+            [color] => {
+                let (r, g, b) = parse_hex_color(color)?;
+                let (h, s, l) = rgb_to_hsl(r, g, b);
+                Ok(DynVal::from(vec![DynVal::from(h), DynVal::from(s), DynVal::from(l)]))
+            }
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        // switch(value, case1, result1, case2, result2, ..., default)
+        "switch" => match args.as_slice() {
+            [value, rest @ ..] if rest.len() >= 3 && rest.len() % 2 == 1 => {
+                let (default, cases) = rest.split_last().unwrap();
+                let result = cases.chunks(2).find(|case| &case[0] == value).map(|case| &case[1]).unwrap_or(default);
+                Ok(result.clone())
+            }
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        // choose(index, option0, option1, ...). Like out-of-range array access, an index that does not
+        // refer to any option results in an empty value rather than an error.
+        "choose" => match args.as_slice() {
+            [index, options @ ..] if !options.is_empty() => {
+                let index = index.as_i32()?;
+                let option = if index < 0 { None } else { options.get(index as usize) };
+                Ok(option.cloned().unwrap_or_else(DynVal::empty))
+            }
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        "to_hex" | "to_binary" | "to_octal" => match args.as_slice() {
+            [num] => {
+                let num = num.as_i64()?;
+                let sign = if num < 0 { "-" } else { "" };
+                let num = num.unsigned_abs();
+                Ok(DynVal::from(match name {
+                    "to_hex" => format!("{}{:x}", sign, num),
+                    "to_binary" => format!("{}{:b}", sign, num),
+                    _ => format!("{}{:o}", sign, num),
+                }))
+            }
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        "from_base" => match args.as_slice() {
+            [string, base] => {
+                let radix = base.as_i32()?;
+                if !(2..=36).contains(&radix) {
+                    return Err(ConversionError { value: base.clone(), target_type: "radix", source: None }.into());
+                }
+                let num = i64::from_str_radix(&string.as_string()?, radix as u32)
+                    .map_err(|e| ConversionError::new(string.clone(), "integer", e))?;
+                Ok(DynVal::from(num.to_string()))
+            }
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        // a whole of 0 results in 0 rather than an error, so that empty progress bars still render.
+        "percent" => match args.as_slice() {
+            [part, whole] => {
+                let part = part.as_f64()?;
+                let whole = whole.as_f64()?;
+                Ok(DynVal::from(finite(if whole == 0.0 { 0.0 } else { part / whole * 100.0 })?))
+            }
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        "percent_of" => match args.as_slice() {
+            [pct, whole] => Ok(DynVal::from(finite(pct.as_f64()? / 100.0 * whole.as_f64()?)?)),
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        #[cfg(feature = "chrono")]
+        "format_time" | "format_time_utc" => match args.as_slice() {
+            [timestamp, format] => {
+                let time = chrono::DateTime::from_timestamp(timestamp.as_f64()? as i64, 0)
+                    .ok_or_else(|| ConversionError { value: timestamp.clone(), target_type: "timestamp", source: None })?;
+                let format = format.as_string()?;
+                Ok(DynVal::from(if name == "format_time" {
+                    format_time(&time.with_timezone(&chrono::Local), &format)?
+                } else {
+                    format_time(&time, &format)?
+                }))
+            }
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        "array_concat" => match args.as_slice() {
+            [] => Err(EvalError::WrongArgCount(name.to_string())),
+            args => {
+                let mut result = Vec::new();
+                for (i, arg) in args.iter().enumerate() {
+                    result.append(&mut json_array_arg(name, i, arg)?);
+                }
+                Ok(DynVal::from(&serde_json::Value::Array(result)))
+            }
+        },
+        // flatten removes a single level of nesting, flatten_deep all of it. Elements that are not arrays are kept as they are.
+        "flatten" | "flatten_deep" => match args.as_slice() {
+            [array] => {
+                let mut result = Vec::new();
+                flatten_json_array(json_array_arg(name, 0, array)?, name == "flatten_deep", &mut result);
+                Ok(DynVal::from(&serde_json::Value::Array(result)))
+            }
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        // `pairs` returns the entries of an object as `[key, value]` arrays, which `object_from_pairs` turns back into an object.
+        // Keys are converted to strings, and for duplicate keys the last value wins.
+        "pairs" => match args.as_slice() {
+            [object] => {
+                let pairs = json_object_arg(name, 0, object)?
+                    .into_iter()
+                    .map(|(key, value)| serde_json::Value::Array(vec![serde_json::Value::String(key), value]))
+                    .collect();
+                Ok(DynVal::from(&serde_json::Value::Array(pairs)))
+            }
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        "object_from_pairs" => match args.as_slice() {
+            [array] => {
+                let mut result = serde_json::Map::new();
+                for (i, pair) in json_array_arg(name, 0, array)?.into_iter().enumerate() {
+                    match pair {
+                        serde_json::Value::Array(pair) if pair.len() == 2 => {
+                            let mut pair = pair.into_iter();
+                            let key = DynVal::from(&pair.next().unwrap_or_default()).0;
+                            result.insert(key, pair.next().unwrap_or_default());
+                        }
+                        _ => return Err(EvalError::WrongElementType(name.to_string(), i, "[key, value] pair")),
+                    }
+                }
+                Ok(DynVal::from(&serde_json::Value::Object(result)))
+            }
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        // object_merge only replaces top-level keys, while object_merge_deep recursively merges nested objects.
+        "object_merge" | "object_merge_deep" => match args.as_slice() {
+            [] => Err(EvalError::WrongArgCount(name.to_string())),
+            args => {
+                let mut result = serde_json::Map::new();
+                for (i, arg) in args.iter().enumerate() {
+                    let object = json_object_arg(name, i, arg)?;
+                    if name == "object_merge_deep" {
+                        merge_json_objects_deep(&mut result, object);
+                    } else {
+                        result.extend(object);
+                    }
+                }
+                Ok(DynVal::from(&serde_json::Value::Object(result)))
+            }
+        },
+        "wrap_text" => match args.as_slice() {
+            [string, width] => {
+                let width = width.as_i32()?.max(1) as usize;
+                Ok(DynVal::from(string.as_string()?.split('\n').map(|line| wrap_line(line, width)).join("\n")))
+            }
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        "slug" => match args.as_slice() {
+            [string] => Ok(DynVal::from(slug(&string.as_string()?))),
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        "replace_map" => match args.as_slice() {
+            [string, replacements] => {
+                let replacements = json_object_arg(name, 1, replacements)?
+                    .iter()
+                    .map(|(from, to)| (from.clone(), DynVal::from(to).0))
+                    .collect_vec();
+                Ok(DynVal::from(replace_map(&string.as_string()?, replacements)))
+            }
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        // text that fits within the given amount of lines is returned unchanged. Otherwise, the remaining lines are replaced
+        // by a single line containing an ellipsis.
+        "clamp_text_lines" => match args.as_slice() {
+            [string, max_lines] => {
+                let max_lines = max_lines.as_i32()?;
+                if max_lines <= 0 {
+                    return Ok(DynVal::empty());
+                }
+                let mut lines = string.as_str().lines();
+                let kept = lines.by_ref().take(max_lines as usize).collect_vec();
+                if lines.next().is_none() {
+                    Ok(string.clone())
+                } else {
+                    Ok(DynVal::from(format!("{}\n…", kept.join("\n"))))
+                }
+            }
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        // a trailing newline does not start another line, so `last_line("a\nb\n")` is "b".
+        "first_line" | "last_line" => match args.as_slice() {
+            [string] => {
+                let mut lines = string.as_str().lines();
+                let line = if name == "first_line" { lines.next() } else { lines.last() };
+                Ok(DynVal::from(line.unwrap_or_default()))
+            }
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        "is_empty" => match args.as_slice() {
+            [string] => Ok(DynVal::from(string.as_str().is_empty())),
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        // any unicode whitespace counts, including non-breaking and ideographic spaces.
+        "is_blank" => match args.as_slice() {
+            [string] => Ok(DynVal::from(string.as_str().trim().is_empty())),
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        "dedent" => match args.as_slice() {
+            [string] => Ok(DynVal::from(dedent(&string.as_string()?))),
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        // empty lines are not indented, to avoid trailing whitespace.
+        "indent" => match args.as_slice() {
+            [string, amount] => {
+                let prefix = " ".repeat(amount.as_i32()?.max(0) as usize);
+                let string = string.as_string()?;
+                let mut lines = string.split('\n').map(|l| if l.is_empty() { l.to_string() } else { format!("{}{}", prefix, l) });
+                Ok(DynVal::from(lines.join("\n")))
+            }
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        // strings are reversed by grapheme cluster, so that combining characters stay attached to their base character.
+        "reverse" => match args.as_slice() {
+            [value] => match value.try_as_json_value() {
+                Some(serde_json::Value::Array(mut values)) => {
+                    values.reverse();
+                    Ok(DynVal::from(&serde_json::Value::Array(values)))
+                }
+                _ => {
+                    use unicode_segmentation::UnicodeSegmentation;
+                    Ok(DynVal::from(value.as_string()?.graphemes(true).rev().collect::<String>()))
+                }
+            },
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        // splits into grapheme clusters, so that emoji made up of multiple code points stay intact.
+        "to_chars" => match args.as_slice() {
+            [string] => {
+                use unicode_segmentation::UnicodeSegmentation;
+                Ok(DynVal::from(string.as_str().graphemes(true).map(DynVal::from).collect_vec()))
+            }
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        "from_chars" => match args.as_slice() {
+            [array] => Ok(json_array_arg(name, 0, array)?.iter().map(DynVal::from).collect()),
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        // elements are compared like with `==`, so "1" and 1.0 are considered duplicates.
+        "unique" => match args.as_slice() {
+            [array] => {
+                let mut seen: Vec<DynVal> = Vec::new();
+                let mut result = Vec::new();
+                for value in json_array_arg(name, 0, array)? {
+                    let dynval = DynVal::from(&value);
+                    if !seen.contains(&dynval) {
+                        seen.push(dynval);
+                        result.push(value);
+                    }
+                }
+                Ok(DynVal::from(&serde_json::Value::Array(result)))
+            }
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        // negative indices count from the end, and out of range bounds are clamped to the array.
+        "slice" => match args.as_slice() {
+            [array, start, rest @ ..] if rest.len() <= 1 => {
+                let values = json_array_arg(name, 0, array)?;
+                let len = values.len() as i64;
+                let resolve = |index: i32| {
+                    let index = index as i64;
+                    (if index < 0 { len + index } else { index }).clamp(0, len) as usize
+                };
+                let start = resolve(start.as_i32()?);
+                let end = match rest {
+                    [end] => resolve(end.as_i32()?),
+                    _ => values.len(),
+                };
+                let result = if start < end { values[start..end].to_vec() } else { Vec::new() };
+                Ok(DynVal::from(&serde_json::Value::Array(result)))
+            }
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        // `clamp_index` clamps to the first or last index, `wrap_index` wraps around like `i` modulo the length,
+        // so -1 is the last index. An empty array has no valid index, and errors.
+        "clamp_index" | "wrap_index" => match args.as_slice() {
+            [array, index] => {
+                let len = json_array_arg(name, 0, array)?.len();
+                let index = index.as_i32()?;
+                if len == 0 {
+                    return Err(EvalError::IndexOutOfRange(index, len));
+                }
+                let len = len as i64;
+                let index = index as i64;
+                Ok(DynVal::from(if name == "clamp_index" { index.clamp(0, len - 1) } else { index.rem_euclid(len) }))
+            }
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        "humanize_duration" => match args.as_slice() {
+            [millis] => Ok(DynVal::from(humanize_duration(millis.as_f64()?.max(0.0) as u64))),
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        // accepts anything `DynVal::as_duration` understands, as well as whitespace separated sums like "1h 2m 3s".
+        "parse_duration" => match args.as_slice() {
+            [string] => {
+                let mut total = std::time::Duration::default();
+                for part in string.as_string()?.split_whitespace() {
+                    total += DynVal(part.to_string(), string.span()).as_duration()?;
+                }
+                Ok(DynVal::from(total.as_millis().to_string()))
+            }
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        // passes the value through unchanged. The label is reported to the debug callback of the `EvalOptions`, if any.
+        "debug" => match args.as_slice() {
+            [value, _label] => Ok(value.clone()),
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        "sort_by_key" | "min_by_key" => match args.as_slice() {
+            [array, key_expr] => {
+                let key_expr = SubExpression::parse(key_expr)?;
+                let mut keyed = json_array_arg(name, 0, array)?
+                    .into_iter()
+                    .map(|item| Ok((key_expr.eval_for_item(&item, options)?, item)))
+                    .collect::<Result<Vec<_>, EvalError>>()?;
+                if name == "min_by_key" {
+                    let min = keyed.into_iter().min_by(|(a, _), (b, _)| compare_values(a, b)).map(|(_, item)| item);
+                    Ok(DynVal::from(&min.unwrap_or(serde_json::Value::Null)))
+                } else {
+                    keyed.sort_by(|(a, _), (b, _)| compare_values(a, b));
+                    Ok(DynVal::from(&serde_json::Value::Array(keyed.into_iter().map(|(_, item)| item).collect())))
+                }
+            }
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        "group_by" => match args.as_slice() {
+            [array, key_expr] => {
+                let key_expr = SubExpression::parse(key_expr)?;
+                let mut groups: BTreeMap<String, Vec<serde_json::Value>> = BTreeMap::new();
+                for item in json_array_arg(name, 0, array)? {
+                    let key = key_expr.eval_for_item(&item, options)?.as_string()?;
+                    groups.entry(key).or_default().push(item);
+                }
+                let groups = groups.into_iter().map(|(key, group)| (key, serde_json::Value::Array(group))).collect();
+                Ok(DynVal::from(&serde_json::Value::Object(groups)))
+            }
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        // `take_while` keeps the leading elements matching the predicate, `drop_while` everything after them.
+        "take_while" | "drop_while" => match args.as_slice() {
+            [array, predicate] => {
+                let predicate = SubExpression::parse(predicate)?;
+                let mut values = json_array_arg(name, 0, array)?;
+                let mut matching = 0;
+                for item in &values {
+                    if !predicate.eval_for_item(item, options)?.as_bool()? {
+                        break;
+                    }
+                    matching += 1;
+                }
+                let rest = values.split_off(matching);
+                Ok(DynVal::from(&serde_json::Value::Array(if name == "take_while" { values } else { rest })))
+            }
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        "count" => match args.as_slice() {
+            [array, predicate] => {
+                let predicate = SubExpression::parse(predicate)?;
+                let mut count = 0;
+                for item in json_array_arg(name, 0, array)? {
+                    if predicate.eval_for_item(&item, options)?.as_bool()? {
+                        count += 1;
+                    }
+                }
+                Ok(DynVal::from(count))
+            }
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        // the sum of an empty array is 0, its product 1, and its average is defined as 0 as well.
+        "sum" | "product" | "average" => match args.as_slice() {
+            [array] => {
+                let to_number =
+                    |(i, x)| DynVal::from(x).as_f64().map_err(|_| EvalError::WrongElementType(name.to_string(), i, "number"));
+                let numbers = json_array_arg(name, 0, array)?.iter().enumerate().map(to_number).collect::<Result<Vec<_>, _>>()?;
+                let sum = numbers.iter().fold(0.0, |acc, x| acc + x);
+                let result = match name {
+                    "sum" => sum,
+                    "product" => numbers.iter().product(),
+                    _ if numbers.is_empty() => 0.0,
+                    _ => sum / numbers.len() as f64,
+                };
+                Ok(DynVal::from(finite(result)?))
+            }
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        // formatted without going through the f64 conversion, as rounding could otherwise produce 1.
+        #[cfg(feature = "rand")]
+        "random" => match args.as_slice() {
+            [] => Ok(DynVal::from(rand::random::<f64>().to_string())),
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        // the bounds are inclusive and may be given in either order.
+        #[cfg(feature = "rand")]
+        "random_range" => match args.as_slice() {
+            [lo, hi] => {
+                use rand::Rng;
+                let (lo, hi) = (lo.as_f64()?.round() as i64, hi.as_f64()?.round() as i64);
+                Ok(DynVal::from(rand::thread_rng().gen_range(lo.min(hi)..=lo.max(hi))))
+            }
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        // reads the environment at evaluation time, so the result may change between evaluations.
+        #[cfg(feature = "env")]
+        "env" => match args.as_slice() {
+            [var_name, default] => Ok(std::env::var(var_name.as_str()).map(DynVal::from).unwrap_or_else(|_| default.clone())),
+            _ => Err(EvalError::WrongArgCount(name.to_string())),
+        },
+        _ => Err(EvalError::UnknownFunction(name.to_string(), closest_match(name, BUILTIN_FUNCTIONS.iter().copied()))),
+    }
+}
+
+/// Order two values numerically if both are numbers, and lexically otherwise.
+fn compare_values(a: &DynVal, b: &DynVal) -> std::cmp::Ordering {
+    a.coerce_like(b).partial_cmp(&b.coerce_like(a)).unwrap_or(std::cmp::Ordering::Equal)
+}
+
+/// Compare two dotted version strings segment by segment. Numeric segments are compared as numbers and order before
+/// non-numeric ones, which are compared lexically. Missing segments count as 0, so `1.2` is equal to `1.2.0`.
+fn compare_versions(a: &str, b: &str) -> std::cmp::Ordering {
+    use itertools::EitherOrBoth::{Both, Left, Right};
+    use std::cmp::Ordering;
+    let compare_segments = |a: &str, b: &str| match (a.parse::<u64>(), b.parse::<u64>()) {
+        (Ok(a), Ok(b)) => a.cmp(&b),
+        (Ok(_), Err(_)) => Ordering::Less,
+        (Err(_), Ok(_)) => Ordering::Greater,
+        (Err(_), Err(_)) => a.cmp(b),
+    };
+    a.split('.')
+        .zip_longest(b.split('.'))
+        .map(|segments| match segments {
+            Both(a, b) => compare_segments(a, b),
+            Left(a) => compare_segments(a, "0"),
+            Right(b) => compare_segments("0", b),
+        })
+        .find(|ordering| *ordering != Ordering::Equal)
+        .unwrap_or(Ordering::Equal)
+}
+
+/// An expression given as a string argument to a builtin, which is evaluated for each element of an array
+/// with the element bound to `$item`.
+struct SubExpression {
+    src: String,
+    expr: SimplExpr,
+}
+
+impl SubExpression {
+    fn parse(src: &DynVal) -> Result<Self, EvalError> {
+        let src = src.as_string()?;
+        match crate::parse_string(0, &src) {
+            Ok(expr) => Ok(SubExpression { src, expr }),
+            Err(err) => Err(EvalError::SubExpression(src, Box::new(err))),
+        }
+    }
+
+    fn eval_for_item(&self, item: &serde_json::Value, options: &EvalOptions) -> Result<DynVal, EvalError> {
+        let mut values = HashMap::new();
+        values.insert("$item".to_string(), DynVal::from(item));
+        self.expr
+            .eval_with_options(&values, options)
+            .map_err(|err| EvalError::SubExpression(self.src.clone(), Box::new(err.into())))
+    }
+}
+
+/// Format a duration given in milliseconds like "1h 2m 3s". Components that are zero are omitted, and the remaining
+/// milliseconds are dropped, unless the whole duration is less than a second, in which case it is shown as e.g. "250ms".
+fn humanize_duration(millis: u64) -> String {
+    if millis < 1000 {
+        return if millis == 0 { "0s".to_string() } else { format!("{}ms", millis) };
+    }
+    let secs = millis / 1000;
+    let components = [(secs / 3600, "h"), (secs / 60 % 60, "m"), (secs % 60, "s")];
+    components.iter().filter(|(amount, _)| *amount > 0).map(|(amount, unit)| format!("{}{}", amount, unit)).join(" ")
+}
+
+fn gcd(mut a: u64, mut b: u64) -> u64 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+fn round_half_even(x: f64) -> f64 {
+    let rounded = x.round();
+    if (rounded - x).abs() == 0.5 {
+        2.0 * (x / 2.0).round()
+    } else {
+        rounded
+    }
+}
+
+/// Lowercase the given string, replacing every run of non-alphanumeric characters with a single hyphen.
+/// Leading and trailing hyphens are removed.
+fn slug(s: &str) -> String {
+    s.split(|c: char| !c.is_alphanumeric()).filter(|word| !word.is_empty()).map(|word| word.to_lowercase()).join("-")
+}
+
+/// Replace the characters that are significant in HTML markup with their entities.
+fn html_escape(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '&' => result.push_str("&amp;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&#39;"),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+/// Decode the common named entities as well as decimal and hexadecimal numeric character references.
+fn html_unescape(s: &str) -> Result<String, EvalError> {
+    let pattern = regex::Regex::new(r"&(?:#([0-9]+)|#[xX]([0-9a-fA-F]+)|([a-zA-Z]+));")?;
+    let result = pattern.replace_all(s, |captures: &regex::Captures| {
+        let decoded = if let Some(decimal) = captures.get(1) {
+            decimal.as_str().parse().ok().and_then(char::from_u32)
+        } else if let Some(hex) = captures.get(2) {
+            u32::from_str_radix(hex.as_str(), 16).ok().and_then(char::from_u32)
+        } else {
+            match &captures[3] {
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "amp" => Some('&'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                "nbsp" => Some('\u{a0}'),
+                _ => None,
+            }
+        };
+        decoded.map(String::from).unwrap_or_else(|| captures[0].to_string())
+    });
+    Ok(result.into_owned())
+}
+
+/// Percent-encode every byte of the UTF-8 representation of the string, except for unreserved characters.
+fn url_encode(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    for byte in s.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => result.push(byte as char),
+            _ => result.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    result
+}
+
+fn url_decode(value: &DynVal) -> Result<String, ConversionError> {
+    let s = value.as_str();
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut i = 0;
+    while i < s.len() {
+        if s.as_bytes()[i] == b'%' {
+            let byte = s
+                .get(i + 1..i + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| ConversionError { value: value.clone(), target_type: "url-encoded string", source: None })?;
+            bytes.push(byte);
+            i += 3;
+        } else {
+            bytes.push(s.as_bytes()[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(bytes).map_err(|e| ConversionError::new(value.clone(), "url-encoded string", e))
+}
+
+/// Apply all the given literal replacements in a single pass, so replaced text is never matched again.
+/// Where multiple keys match at the same position, the longest one wins.
+fn replace_map(s: &str, mut replacements: Vec<(String, String)>) -> String {
+    replacements.retain(|(from, _)| !from.is_empty());
+    replacements.sort_by_key(|(from, _)| std::cmp::Reverse(from.len()));
+    let mut result = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(c) = rest.chars().next() {
+        match replacements.iter().find(|(from, _)| rest.starts_with(from.as_str())) {
+            Some((from, to)) => {
+                result.push_str(to);
+                rest = &rest[from.len()..];
+            }
+            None => {
+                result.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+    result
+}
+
+/// Remove the longest whitespace prefix shared by all lines. Lines consisting only of whitespace are not considered
+/// when computing the prefix, and are emptied.
+fn dedent(s: &str) -> String {
+    let prefix = s
+        .split('\n')
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| &line[..line.len() - line.trim_start().len()])
+        .fold(None, |common: Option<&str>, indent| match common {
+            None => Some(indent),
+            Some(common) => {
+                let shared = common.char_indices().zip(indent.chars()).find(|((_, a), b)| a != b).map(|((i, _), _)| i);
+                Some(&common[..shared.unwrap_or_else(|| common.len().min(indent.len()))])
+            }
+        })
+        .unwrap_or_default();
+    s.split('\n').map(|line| if line.trim().is_empty() { "" } else { &line[prefix.len()..] }).join("\n")
+}
+
+/// Break a single line into lines of at most `width` columns, measured in display width.
+/// Lines are broken on whitespace, and words are only split if they are wider than `width` by themselves.
+fn wrap_line(line: &str, width: usize) -> String {
+    use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+    let mut lines = Vec::new();
+    let mut current = String::new();
+    let mut current_width = 0;
+    for word in line.split_whitespace() {
+        let word_width = word.width();
+        if current_width > 0 && current_width + 1 + word_width <= width {
+            current.push(' ');
+            current.push_str(word);
+            current_width += 1 + word_width;
+            continue;
+        }
+        if current_width > 0 {
+            lines.push(std::mem::take(&mut current));
+            current_width = 0;
+        }
+        for c in word.chars() {
+            let char_width = c.width().unwrap_or(0);
+            if current_width > 0 && current_width + char_width > width {
+                lines.push(std::mem::take(&mut current));
+                current_width = 0;
+            }
+            current.push(c);
+            current_width += char_width;
+        }
+    }
+    lines.push(current);
+    lines.join("\n")
+}
+
+/// Read the argument at the given (zero-based) index of a function call as a json object.
+fn json_object_arg(
+    name: &str,
+    index: usize,
+    arg: &DynVal,
+) -> Result<serde_json::Map<String, serde_json::Value>, EvalError> {
+    match arg.try_as_json_value() {
+        Some(serde_json::Value::Object(values)) => Ok(values),
+        _ => Err(EvalError::WrongArgType(name.to_string(), index + 1, "json object")),
+    }
+}
+
+/// Merge `other` into `target`, recursing into objects present in both. Any other value in `other` replaces the one in `target`.
+fn merge_json_objects_deep(
+    target: &mut serde_json::Map<String, serde_json::Value>,
+    other: serde_json::Map<String, serde_json::Value>,
+) {
+    for (key, value) in other {
+        match (target.get_mut(&key), value) {
+            (Some(serde_json::Value::Object(existing)), serde_json::Value::Object(value)) => {
+                merge_json_objects_deep(existing, value)
+            }
+            (_, value) => {
+                target.insert(key, value);
+            }
+        }
+    }
+}
+
+fn flatten_json_array(values: Vec<serde_json::Value>, deep: bool, result: &mut Vec<serde_json::Value>) {
+    for value in values {
+        match value {
+            serde_json::Value::Array(inner) if deep => flatten_json_array(inner, deep, result),
+            serde_json::Value::Array(mut inner) => result.append(&mut inner),
+            value => result.push(value),
+        }
+    }
+}
+
+/// Read the argument at the given (zero-based) index of a function call as a json array.
+fn json_array_arg(name: &str, index: usize, arg: &DynVal) -> Result<Vec<serde_json::Value>, EvalError> {
+    match arg.try_as_json_value() {
+        Some(serde_json::Value::Array(values)) => Ok(values),
+        _ => Err(EvalError::WrongArgType(name.to_string(), index + 1, "json array")),
+    }
+}
+
+/// Format the given time using a `strftime`-style format string, erroring on invalid format specifiers
+/// rather than panicking like chrono would.
+#[cfg(feature = "chrono")]
+fn format_time<Tz: chrono::TimeZone>(time: &chrono::DateTime<Tz>, format: &str) -> Result<String, EvalError>
+where
+    Tz::Offset: std::fmt::Display,
+{
+    use chrono::format::{Item, StrftimeItems};
+    let items = StrftimeItems::new(format).collect::<Vec<_>>();
+    if items.iter().any(|item| matches!(item, Item::Error)) {
+        return Err(EvalError::InvalidTimeFormat(format.to_string()));
+    }
+    Ok(time.format_with_items(items.into_iter()).to_string())
+}
+
+/// Format the given channels (0-255, clamped and rounded) as a `#rrggbb` color.
+fn format_hex_color(r: f64, g: f64, b: f64) -> String {
+    let channel = |x: f64| x.clamp(0.0, 255.0).round() as u8;
+    format!("#{:02x}{:02x}{:02x}", channel(r), channel(g), channel(b))
+}
+
+/// Parse a `#rrggbb` color (the `#` being optional) into its channels.
+fn parse_hex_color(color: &DynVal) -> Result<(f64, f64, f64), ConversionError> {
+    let hex = color.0.trim_start_matches('#');
+    let expanded;
+    let hex = if hex.len() == 3 {
+        expanded = hex.chars().map(|c| format!("{0}{0}", c)).collect::<String>();
+        &expanded
+    } else {
+        hex
+    };
+    let channel = |i: usize| hex.get(i..i + 2).and_then(|x| u8::from_str_radix(x, 16).ok()).map(f64::from);
+    match (hex.len(), channel(0), channel(2), channel(4)) {
+        (6, Some(r), Some(g), Some(b)) => Ok((r, g, b)),
+        _ => Err(ConversionError { value: color.clone(), target_type: "color", source: None }),
+    }
+}
+
+/// Convert a color given as hue in degrees (0-360, wrapping around) and saturation and lightness (0-1, clamped)
+/// into rgb channels ranging from 0 to 255.
+fn hsl_to_rgb(h: f64, s: f64, l: f64) -> (f64, f64, f64) {
+    let h = h.rem_euclid(360.0) / 60.0;
+    let s = s.clamp(0.0, 1.0);
+    let l = l.clamp(0.0, 1.0);
+    let chroma = (1.0 - (2.0 * l - 1.0).abs()) * s;
+    let x = chroma * (1.0 - (h % 2.0 - 1.0).abs());
+    let (r, g, b) = match h as u8 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let m = l - chroma / 2.0;
+    ((r + m) * 255.0, (g + m) * 255.0, (b + m) * 255.0)
+}
+
+/// Convert rgb channels ranging from 0 to 255 into hue in degrees (0-360), and saturation and lightness (0-1).
+fn rgb_to_hsl(r: f64, g: f64, b: f64) -> (f64, f64, f64) {
+    let (r, g, b) = (r / 255.0, g / 255.0, b / 255.0);
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let l = (max + min) / 2.0;
+    let delta = max - min;
+    if delta == 0.0 {
+        return (0.0, 0.0, l);
+    }
+    let s = delta / (1.0 - (2.0 * l - 1.0).abs());
+    let h = if max == r {
+        ((g - b) / delta).rem_euclid(6.0)
+    } else if max == g {
+        (b - r) / delta + 2.0
+    } else {
+        (r - g) / delta + 4.0
+    };
+    (h * 60.0, s, l)
+}
+
+/// Uppercase the first character of the string, leaving the rest untouched.
+/// The locale is given as a BCP-47 tag; only its language subtag is considered.
+/// Turkish and Azeri map `i` to `İ`, any other or empty locale uses the default unicode mapping.
+fn capitalize_locale(s: &str, locale: &str) -> String {
+    let mut chars = s.chars();
+    let first = match chars.next() {
+        Some(first) => first,
+        None => return String::new(),
+    };
+    let language = locale.split(&['-', '_'][..]).next().unwrap_or_default().to_lowercase();
+    let mut result = match (first, language.as_str()) {
+        ('i', "tr") | ('i', "az") => "İ".to_string(),
+        _ => first.to_uppercase().collect(),
+    };
+    result.push_str(chars.as_str());
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval_str(s: &str) -> Result<DynVal, EvalError> {
+        crate::parse_string(0, s).unwrap().eval_no_vars()
+    }
+
+    fn call_expr_function(name: &str, args: Vec<DynVal>) -> Result<DynVal, EvalError> {
+        super::call_expr_function(name, args, &EvalOptions::default())
+    }
+
+    #[test]
+    fn test_matches_any() {
+        let matches_any =
+            |s: &str, patterns: &str| call_expr_function("matches_any", vec![DynVal::from(s), DynVal::from(patterns)]);
+        assert_eq!(matches_any("/home/user", r#"["^/home", "^/root"]"#).unwrap().0, "true");
+        assert_eq!(matches_any("/root/x", r#"["^/home", "^/root", "("]"#).unwrap().0, "true");
+        assert_eq!(matches_any("/etc", r#"["^/home", "^/root"]"#).unwrap().0, "false");
+        assert_eq!(matches_any("/etc", "[]").unwrap().0, "false");
+        let err = matches_any("/etc", r#"["^/home", "^(/etc"]"#).unwrap_err();
+        assert!(err.to_string().contains("^(/etc"), "{}", err);
+        assert!(matches!(matches_any("/etc", r#"["^/home", 1]"#), Err(EvalError::WrongElementType(_, 1, _))));
+        assert!(matches!(matches_any("/etc", "\"^/etc\""), Err(EvalError::WrongArgType(_, 2, _))));
+    }
+
+    #[cfg(feature = "glob")]
+    #[test]
+    fn test_matches_glob() {
+        assert_eq!(eval_str("matches_glob(\"src/main.rs\", \"*.rs\")").unwrap().0, "true");
+        assert_eq!(eval_str("matches_glob(\"file1.txt\", \"file?.txt\")").unwrap().0, "true");
+        assert_eq!(eval_str("matches_glob(\"file10.txt\", \"file?.txt\")").unwrap().0, "false");
+        assert_eq!(eval_str("matches_glob(\"b.log\", \"[a-c].log\")").unwrap().0, "true");
+        assert_eq!(eval_str("matches_glob(\"d.log\", \"[!a-c].log\")").unwrap().0, "true");
+        assert_eq!(eval_str("matches_glob(\"main.rs\", \"*.txt\")").unwrap().0, "false");
+        let err = eval_str("matches_glob(\"a\", \"[a-\")").unwrap_err();
+        assert!(matches!(err, EvalError::Spanned(_, box EvalError::InvalidGlob(..))));
+        assert!(err.to_string().starts_with("Invalid glob pattern `[a-`"), "{}", err);
+    }
+
+    #[cfg(feature = "base64")]
+    #[test]
+    fn test_base64() {
+        let encode = |s: &str| call_expr_function("base64_encode", vec![DynVal::from(s)]).unwrap().0;
+        let decode = |s: &str| call_expr_function("base64_decode", vec![DynVal::from(s)]);
+        assert_eq!(encode("hello world"), "aGVsbG8gd29ybGQ=");
+        assert_eq!(encode(""), "");
+        assert_eq!(encode("ünïcode"), "w7xuw69jb2Rl");
+        assert_eq!(decode("aGVsbG8gd29ybGQ=").unwrap().0, "hello world");
+        for original in &["a", "ab", "abc", "{\"key\": [1, 2]}", "ünïcode ☕"] {
+            assert_eq!(decode(&encode(original)).unwrap().0, *original);
+        }
+
+        let err = decode("not base64!").unwrap_err();
+        assert!(matches!(err, EvalError::InvalidBase64(..)));
+        assert!(err.to_string().starts_with("Invalid base64 `not base64!`"), "{}", err);
+        assert!(matches!(decode("aGVsb"), Err(EvalError::InvalidBase64(..))));
+        let err = decode("/w==").unwrap_err();
+        assert!(matches!(err, EvalError::ConversionError(_)));
+        assert!(err.to_string().contains("utf-8"), "{}", err);
+    }
+
+    #[cfg(feature = "hashes")]
+    #[test]
+    fn test_hashes() {
+        let hash = |name: &str, s: &str| call_expr_function(name, vec![DynVal::from(s)]).unwrap().0;
+        assert_eq!(hash("hash_md5", "hello world"), "5eb63bbbe01eeed093cb22bb8f5acdc3");
+        assert_eq!(hash("hash_md5", ""), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(hash("hash_sha256", "hello world"), "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9");
+        assert_eq!(hash("hash_sha256", ""), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+    }
+
+    #[test]
+    fn test_escape_json() {
+        let escape = |s: &str| call_expr_function("escape_json", vec![DynVal::from(s)]).unwrap().0;
+        let unescape = |s: &str| call_expr_function("unescape_json", vec![DynVal::from(s)]);
+        assert_eq!(escape(r#"say "hi""#), r#"say \"hi\""#);
+        assert_eq!(escape(r"C:\dir"), r"C:\\dir");
+        assert_eq!(escape("a\nb\tc\rd"), r"a\nb\tc\rd");
+        assert_eq!(escape("\u{1}\u{1f}"), r"\u0001\u001f");
+        assert_eq!(escape("plain ünïcode"), "plain ünïcode");
+
+        assert_eq!(unescape(r#"say \"hi\""#).unwrap().0, r#"say "hi""#);
+        assert_eq!(unescape(r"C:\\dir").unwrap().0, r"C:\dir");
+        assert_eq!(unescape(r"a\nb\tc\rd\/").unwrap().0, "a\nb\tc\rd/");
+        assert_eq!(unescape(r"\u00e9\u0001 \ud83d\ude00").unwrap().0, "é\u{1} 😀");
+        assert!(matches!(unescape(r"bad \x escape"), Err(EvalError::ConversionError(_))));
+        assert!(matches!(unescape(r#"unescaped " quote"#), Err(EvalError::ConversionError(_))));
+        assert!(matches!(unescape(r"trailing \"), Err(EvalError::ConversionError(_))));
+
+        let original = "{\"key\": \"line\\nbreak\"}\n\ttab \u{7} bell";
+        assert_eq!(unescape(&escape(original)).unwrap().0, original);
+    }
+
+    #[test]
+    fn test_html_escape() {
+        let escape = |s: &str| call_expr_function("html_escape", vec![DynVal::from(s)]).unwrap().0;
+        let unescape = |s: &str| call_expr_function("html_unescape", vec![DynVal::from(s)]).unwrap().0;
+        assert_eq!(escape("<"), "&lt;");
+        assert_eq!(escape(">"), "&gt;");
+        assert_eq!(escape("&"), "&amp;");
+        assert_eq!(escape("\""), "&quot;");
+        assert_eq!(escape("'"), "&#39;");
+        assert_eq!(escape(r#"<b class="x">Tom & Jerry's</b>"#), "&lt;b class=&quot;x&quot;&gt;Tom &amp; Jerry&#39;s&lt;/b&gt;");
+
+        assert_eq!(unescape("&lt;&gt;&amp;&quot;&apos;&#39;&nbsp;"), "<>&\"''\u{a0}");
+        assert_eq!(unescape("&#233;&#xE9;&#X1F600;"), "éé😀");
+        assert_eq!(unescape("&amp;lt;"), "&lt;");
+        assert_eq!(unescape("&unknown; &#xD800; & &lt"), "&unknown; &#xD800; & &lt");
+
+        let original = r#"<script>alert("hi & 'bye'")</script>"#;
+        assert_eq!(unescape(&escape(original)), original);
+    }
+
+    #[test]
+    fn test_url_encode() {
+        let encode = |s: &str| call_expr_function("url_encode", vec![DynVal::from(s)]).unwrap().0;
+        let decode = |s: &str| call_expr_function("url_decode", vec![DynVal::from(s)]);
+        assert_eq!(encode("hello world"), "hello%20world");
+        assert_eq!(encode("a+b=c&d/e?f#g"), "a%2Bb%3Dc%26d%2Fe%3Ff%23g");
+        assert_eq!(encode("unreserved-_.~AZaz09"), "unreserved-_.~AZaz09");
+        assert_eq!(encode("café ☕"), "caf%C3%A9%20%E2%98%95");
+
+        assert_eq!(decode("hello%20world").unwrap().0, "hello world");
+        assert_eq!(decode("a%2bb%3Dc+d").unwrap().0, "a+b=c+d");
+        assert_eq!(decode("caf%C3%A9%20%E2%98%95").unwrap().0, "café ☕");
+        assert_eq!(decode("already decoded ü").unwrap().0, "already decoded ü");
+        assert!(matches!(decode("100%"), Err(EvalError::ConversionError(_))));
+        assert!(matches!(decode("%zz"), Err(EvalError::ConversionError(_))));
+        assert!(matches!(decode("%C3"), Err(EvalError::ConversionError(_))));
+
+        let original = "search?q=rust & simplexpr: 100% ünïcode";
+        assert_eq!(decode(&encode(original)).unwrap().0, original);
+    }
+
+    #[test]
+    fn test_strip_ansi() {
+        let strip_ansi = |s: &str| call_expr_function("strip_ansi", vec![DynVal::from(s)]).unwrap().0;
+        assert_eq!(strip_ansi("\x1b[31mred\x1b[0m and \x1b[1;32mbold green\x1b[m"), "red and bold green");
+        assert_eq!(strip_ansi("\x1b[38;5;208morange\x1b[39m"), "orange");
+        assert_eq!(strip_ansi("up\x1b[2Aline\x1b[K\x1b[?25l"), "upline");
+        assert_eq!(strip_ansi("plain [text] 50%"), "plain [text] 50%");
+    }
+
+    #[test]
+    fn test_clamp01() {
+        assert_eq!(eval_str("clamp01(0.5)").unwrap(), DynVal::from(0.5));
+        assert_eq!(eval_str("clamp01(-3)").unwrap(), DynVal::from(0));
+        assert_eq!(eval_str("clamp01(7)").unwrap(), DynVal::from(1));
+    }
+
+    #[test]
+    fn test_trunc_fract() {
+        assert_eq!(eval_str("trunc(2.7)").unwrap().0, "2");
+        assert_eq!(eval_str("trunc(-2.7)").unwrap().0, "-2");
+        assert_eq!(eval_str("trunc(-0.5)").unwrap().0, "0");
+        assert_eq!(eval_str("fract(2.75)").unwrap().0, "0.75");
+        assert_eq!(eval_str("fract(-2.7)").unwrap().0, "-0.7");
+        assert_eq!(eval_str("fract(-3)").unwrap().0, "0");
+        assert!(eval_str("trunc(1, 2)").is_err());
+    }
+
+    #[test]
+    fn test_sign() {
+        assert_eq!(eval_str("sign(-3.5)").unwrap().0, "-1");
+        assert_eq!(eval_str("sign(0)").unwrap().0, "0");
+        assert_eq!(eval_str("sign(-0.0)").unwrap().0, "0");
+        assert_eq!(eval_str("sign(0.001)").unwrap().0, "1");
+        assert!(eval_str("sign(\"a\")").is_err());
+    }
+
+    #[test]
+    fn test_checked_arithmetic() {
+        assert_eq!(eval_str("add_checked(9223372036854775806, 1)").unwrap().0, "9223372036854775807");
+        assert_eq!(eval_str("add_checked(-9223372036854775807, -1)").unwrap().0, "-9223372036854775808");
+        assert_eq!(eval_str("mul_checked(4611686018427387903, 2)").unwrap().0, "9223372036854775806");
+        let err = eval_str("add_checked(9223372036854775807, 1)").unwrap_err();
+        assert!(matches!(err, EvalError::Spanned(_, box EvalError::Overflow(_))));
+        assert!(eval_str("add_checked(-9223372036854775808, -1)").is_err());
+        assert!(eval_str("mul_checked(4611686018427387904, 2)").is_err());
+        assert!(eval_str("add_checked(1.5, 1)").is_err());
+    }
+
+    #[test]
+    fn test_gcd_lcm() {
+        assert_eq!(eval_str("gcd(8, 15)").unwrap().0, "1");
+        assert_eq!(eval_str("lcm(8, 15)").unwrap().0, "120");
+        assert_eq!(eval_str("gcd(1920, 1080)").unwrap().0, "120");
+        assert_eq!(eval_str("lcm(4, 6)").unwrap().0, "12");
+        assert_eq!(eval_str("gcd(-12, 18)").unwrap().0, "6");
+        assert_eq!(eval_str("lcm(-4, 6)").unwrap().0, "12");
+        assert_eq!(eval_str("gcd(0, 7)").unwrap().0, "7");
+        assert_eq!(eval_str("gcd(0, 0)").unwrap().0, "0");
+        assert_eq!(eval_str("lcm(0, 7)").unwrap().0, "0");
+        assert!(eval_str("gcd(1.5, 3)").is_err());
+    }
+
+    #[test]
+    fn test_ceil_div() {
+        assert_eq!(eval_str("ceil_div(10, 5)").unwrap().0, "2");
+        assert_eq!(eval_str("ceil_div(11, 5)").unwrap().0, "3");
+        assert_eq!(eval_str("ceil_div(0, 5)").unwrap().0, "0");
+        assert_eq!(eval_str("ceil_div(-11, 5)").unwrap().0, "-2");
+        assert_eq!(eval_str("ceil_div(11, -5)").unwrap().0, "-2");
+        assert_eq!(eval_str("ceil_div(-11, -5)").unwrap().0, "3");
+        assert_eq!(eval_str("ceil_div(9007199254740993, 1)").unwrap().0, "9007199254740993");
+        let err = eval_str("ceil_div(1, 0)").unwrap_err();
+        assert!(matches!(err, EvalError::Spanned(_, box EvalError::DivisionByZero)));
+        assert!(eval_str("ceil_div(1.5, 1)").is_err());
+    }
+
+    #[test]
+    fn test_version_compare() {
+        assert_eq!(eval_str("version_compare(\"1.10.0\", \"1.9.0\")").unwrap().0, "1");
+        assert_eq!(eval_str("version_compare(\"1.9\", \"1.10\")").unwrap().0, "-1");
+        assert_eq!(eval_str("version_compare(\"2.0.1\", \"2.0.1\")").unwrap().0, "0");
+        assert_eq!(eval_str("version_compare(\"1.2\", \"1.2.0\")").unwrap().0, "0");
+        assert_eq!(eval_str("version_compare(\"1.2\", \"1.2.1\")").unwrap().0, "-1");
+        assert_eq!(eval_str("version_compare(\"1.2.beta\", \"1.2.3\")").unwrap().0, "1");
+        assert_eq!(eval_str("version_compare(\"1.alpha\", \"1.beta\")").unwrap().0, "-1");
+        assert_eq!(eval_str("version_compare(1.10, 1.9)").unwrap().0, "1");
+    }
+
+    #[test]
+    fn test_parse_number_with_unit() {
+        let parse = |s: &str| call_expr_function("parse_number_with_unit", vec![DynVal::from(s)]);
+        assert_eq!(parse("12px").unwrap().as_json_value().unwrap(), serde_json::json!({"value": 12, "unit": "px"}));
+        assert_eq!(parse("-1.5em").unwrap().as_json_value().unwrap(), serde_json::json!({"value": -1.5, "unit": "em"}));
+        assert_eq!(parse(".5 rem").unwrap().as_json_value().unwrap(), serde_json::json!({"value": 0.5, "unit": "rem"}));
+        assert_eq!(parse("42").unwrap().as_json_value().unwrap(), serde_json::json!({"value": 42, "unit": ""}));
+        assert_eq!(parse("50%").unwrap().as_json_value().unwrap(), serde_json::json!({"value": 50, "unit": "%"}));
+        assert_eq!(parse("1e3ms").unwrap().as_json_value().unwrap(), serde_json::json!({"value": 1000, "unit": "ms"}));
+        assert_eq!(parse("3null").unwrap().as_json_value().unwrap(), serde_json::json!({"value": 3, "unit": "null"}));
+        assert!(parse("px").is_err());
+        assert!(parse("").is_err());
+        assert_eq!(eval_str("parse_number_with_unit(\"12px\").value * 2").unwrap().0, "24");
+    }
+
+    #[test]
+    fn test_as_fraction() {
+        assert_eq!(eval_str("as_fraction(\"50%\")").unwrap().0, "0.5");
+        assert_eq!(eval_str("as_fraction(\"0.5\")").unwrap().0, "0.5");
+        assert_eq!(eval_str("as_fraction(\"33.3%\") * 300").unwrap().0, "99.9");
+        assert!(eval_str("as_fraction(\"fifty%\")").is_err());
+    }
+
+    #[test]
+    fn test_lerp() {
+        assert_eq!(eval_str("lerp(10, 20, 0)").unwrap(), DynVal::from(10));
+        assert_eq!(eval_str("lerp(10, 20, 0.5)").unwrap(), DynVal::from(15));
+        assert_eq!(eval_str("lerp(10, 20, 1)").unwrap(), DynVal::from(20));
+        assert_eq!(eval_str("lerp(10, 20, 2)").unwrap(), DynVal::from(30));
+        assert!(eval_str("lerp(1, 2)").is_err());
+    }
+
+    #[test]
+    fn test_function_registry() {
+        let mut registry = FunctionRegistry::new();
+        registry.register("greet", |args| match args {
+            [name] => Ok(DynVal::from(format!("Hello, {}!", name.as_string()?))),
+            _ => Err(EvalError::WrongArgCount("greet".to_string())),
+        });
+        let expr = crate::parse_string(0, "greet(name) + \" \" + lerp(0, 10, 0.5)").unwrap();
+        let vars = maplit::hashmap! { "name".to_string() => DynVal::from("world") };
+        assert_eq!(expr.eval_with_registry(&vars, &registry).unwrap(), DynVal::from("Hello, world! 5"));
+        assert!(crate::parse_string(0, "greet(\"a\", \"b\")").unwrap().eval_with_registry(&vars, &registry).is_err());
+        assert!(crate::parse_string(0, "greet(\"a\")").unwrap().eval(&vars).is_err());
+
+        let options = EvalOptions::new().with_registry(&registry).with_lenient_indexing(true);
+        assert_eq!(crate::parse_string(0, "greet(name[0])").unwrap().eval_with_options(&vars, &options).unwrap().0, "Hello, !");
+    }
+
+    #[test]
+    fn test_sub_expressions_use_options() {
+        let mut registry = FunctionRegistry::new();
+        registry.register("negate", |args| Ok(DynVal::from(-args[0].as_f64()?)));
+        let vars = maplit::hashmap! { "nums".to_string() => DynVal::from("[2, 3, 1]") };
+        let expr = crate::parse_string(0, "sort_by_key(nums, \"negate($item)\")").unwrap();
+        assert!(expr.eval(&vars).is_err());
+        let sorted = expr.eval_with_registry(&vars, &registry).unwrap();
+        assert_eq!(sorted.as_json_value().unwrap(), serde_json::json!([3, 2, 1]));
+
+        let expr = crate::parse_string(0, "min_by_key(nums, \"$item[0]\")").unwrap();
+        assert!(expr.eval(&vars).is_err());
+        let lenient = EvalOptions::new().with_lenient_indexing(true);
+        assert_eq!(expr.eval_with_options(&vars, &lenient).unwrap(), DynVal::from(2));
+    }
+
+    #[test]
+    fn test_to_json() {
+        let vars = maplit::hashmap! { "obj".to_string() => DynVal::from(r#"{"a": [1, 2]}"#) };
+        let eval = |s: &str| crate::parse_string(0, s).unwrap().eval(&vars).unwrap().0;
+        assert_eq!(eval("to_json(obj)"), r#"{"a":[1,2]}"#);
+        assert_eq!(eval("to_json_pretty(obj)"), "{\n  \"a\": [\n    1,\n    2\n  ]\n}");
+        assert_eq!(eval("to_json(5)"), "5");
+        assert_eq!(eval("to_json_pretty(5)"), "5");
+        assert_eq!(eval("to_json_pretty(\"hi\")"), "\"hi\"");
+    }
+
+    #[test]
+    fn test_count_matches() {
+        assert_eq!(eval_str("count_matches(\"hello\", \"x\")").unwrap(), DynVal::from(0));
+        assert_eq!(eval_str("count_matches(\"a1b22c333\", \"[0-9]+\")").unwrap(), DynVal::from(3));
+        assert_eq!(eval_str("count_matches(\"aaaa\", \"aa\")").unwrap(), DynVal::from(2));
+        assert_eq!(eval_str("count_matches(\"abc\", \"\")").unwrap(), DynVal::from(4));
+        assert!(eval_str("count_matches(\"abc\", \"(\")").is_err());
+    }
+
+    #[test]
+    fn test_capitalize_locale() {
+        assert_eq!(eval_str("capitalize_locale(\"istanbul\", \"tr\")").unwrap().0, "İstanbul");
+        assert_eq!(eval_str("capitalize_locale(\"istanbul\", \"az-Latn-AZ\")").unwrap().0, "İstanbul");
+        assert_eq!(eval_str("capitalize_locale(\"istanbul\", \"\")").unwrap().0, "Istanbul");
+        assert_eq!(eval_str("capitalize_locale(\"istanbul\", \"en-US\")").unwrap().0, "Istanbul");
+        assert_eq!(eval_str("capitalize_locale(\"ßtraße\", \"de\")").unwrap().0, "SStraße");
+        assert_eq!(eval_str("capitalize_locale(\"\", \"tr\")").unwrap().0, "");
+    }
+
+    #[test]
+    fn test_error_to_json() {
+        let err = eval_str("round(1, 2, 3)").unwrap_err();
+        assert_eq!(
+            err.to_json(),
+            serde_json::json!({
+                "kind": "WrongArgCount",
+                "message": "Incorrect number of arguments given to function: round",
+                "span": [0, 14],
+            })
+        );
+        let err = eval_str("1 + ceil_div(4, 0)").unwrap_err();
+        assert_eq!(
+            err.to_json(),
+            serde_json::json!({ "kind": "DivisionByZero", "message": "Division by zero", "span": [4, 18] })
+        );
+        let json = EvalError::UnknownFunction("rond".to_string(), Some("round".to_string())).to_json();
+        assert_eq!(json["kind"], "UnknownFunction");
+        assert_eq!(json["message"], "Unknown function rond, did you mean round?");
+        assert_eq!(json["span"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn test_is_user_error() {
+        let conversion_error = DynVal::from("a").as_f64().unwrap_err();
+        assert!(EvalError::NoVariablesAllowed("a".to_string()).is_user_error());
+        assert!(EvalError::InvalidRegex(regex::Error::Syntax("(".to_string())).is_user_error());
+        assert!(EvalError::UnresolvedVariable("a".to_string()).is_user_error());
+        assert!(EvalError::ConversionError(conversion_error).is_user_error());
+        assert!(EvalError::WrongArgCount("round".to_string()).is_user_error());
+        assert!(EvalError::WrongArgType("round".to_string(), 1, "number").is_user_error());
+        assert!(EvalError::WrongElementType("sum".to_string(), 0, "number").is_user_error());
+        assert!(EvalError::UnknownVariable("a".to_string()).is_user_error());
+        assert!(EvalError::CannotIndex("a".to_string()).is_user_error());
+        assert!(EvalError::IndexOutOfRange(5, 3).is_user_error());
+        assert!(EvalError::InvalidTimeFormat("%Q".to_string()).is_user_error());
+        assert!(EvalError::InvalidGlob("[".to_string(), "invalid range pattern".to_string()).is_user_error());
+        assert!(EvalError::InvalidBase64("!".to_string(), "Invalid byte 33, offset 0.".to_string()).is_user_error());
+        assert!(EvalError::DivisionByZero.is_user_error());
+        assert!(EvalError::NonFiniteResult(f64::NAN).is_user_error());
+        assert!(EvalError::Overflow("add_checked".to_string()).is_user_error());
+        assert!(!EvalError::UnknownFunction("a".to_string(), None).is_user_error());
+        assert!(!EvalError::UnknownFunction("a".to_string(), None).at(Span(0, 1, 0)).is_user_error());
+        assert!(EvalError::CannotIndex("a".to_string()).at(Span(0, 1, 0)).is_user_error());
+    }
+
+    #[test]
+    fn test_boolean_round_trip() {
+        for input in ["1 < 2", "2 < 1", "\"a\" == \"a\"", "!true", "1 == 1.0 && 2 != 3"].iter() {
+            let result = eval_str(input).unwrap();
+            assert_eq!(result, DynVal::from_bool(result.as_bool().unwrap()), "{}", input);
+        }
+        assert_eq!(eval_str("true == \"true\"").unwrap().0, "true");
+        assert_eq!(eval_str("(1 < 2) == true").unwrap().0, "true");
+        assert_eq!(eval_str("(1 > 2) == \"false\"").unwrap().0, "true");
+    }
+
+    #[test]
+    fn test_at_keeps_innermost_span() {
+        let err = EvalError::DivisionByZero.at(Span(4, 5, 0)).at(Span(0, 10, 0));
+        assert!(matches!(err, EvalError::Spanned(Span(4, 5, 0), box EvalError::DivisionByZero)));
+        assert_eq!(err.to_string(), "Division by zero");
+
+        let err = eval_str("1 + round(round_to_multiple(1, 0), 2)").unwrap_err();
+        assert!(matches!(err, EvalError::Spanned(Span(10, 33, 0), box EvalError::DivisionByZero)));
+    }
+
+    #[test]
+    fn test_colors() {
+        assert_eq!(eval_str("rgb(255, 0, 0)").unwrap().0, "#ff0000");
+        assert_eq!(eval_str("rgb(300, -5, 127.6)").unwrap().0, "#ff0080");
+        assert_eq!(eval_str("hsl(0, 1, 0.5)").unwrap().0, "#ff0000");
+        assert_eq!(eval_str("hsl(120, 1, 0.5)").unwrap().0, "#00ff00");
+        assert_eq!(eval_str("hsl(240, 1, 0.5)").unwrap().0, "#0000ff");
+        assert_eq!(eval_str("hsl(600, 1, 0.5)").unwrap().0, "#0000ff");
+        assert_eq!(eval_str("hsl(0, 0, 1)").unwrap().0, "#ffffff");
+        assert_eq!(eval_str("rgb_to_hsl(\"#00ff00\")").unwrap().as_json_value().unwrap(), serde_json::json!([120, 1, 0.5]));
+        assert_eq!(eval_str("rgb_to_hsl(\"000000\")").unwrap().as_json_value().unwrap(), serde_json::json!([0, 0, 0]));
+        assert!(eval_str("rgb_to_hsl(\"#00ff0\")").is_err());
+
+        let color = "#3a7bd5";
+        let hsl = eval_str(&format!("rgb_to_hsl(\"{}\")", color)).unwrap().as_json_value().unwrap();
+        let round_tripped = eval_str(&format!("hsl({}, {}, {})", hsl[0], hsl[1], hsl[2])).unwrap();
+        assert_eq!(round_tripped.0, color);
+    }
+
+    #[test]
+    fn test_hex_rgb_conversion() {
+        assert_eq!(eval_str("hex_to_rgb(\"#3a7bd5\")").unwrap().as_json_value().unwrap(), serde_json::json!([58, 123, 213]));
+        assert_eq!(eval_str("hex_to_rgb(\"#fff\")").unwrap().as_json_value().unwrap(), serde_json::json!([255, 255, 255]));
+        assert_eq!(eval_str("hex_to_rgb(\"a0c\")").unwrap().as_json_value().unwrap(), serde_json::json!([170, 0, 204]));
+        assert!(eval_str("hex_to_rgb(\"#ff\")").is_err());
+        assert!(eval_str("hex_to_rgb(\"#gggggg\")").is_err());
+        assert_eq!(eval_str("rgb_to_hex(58, 123, 213)").unwrap().0, "#3a7bd5");
+        assert_eq!(eval_str("rgb_to_hex(0, 0, 0)").unwrap().0, "#000000");
+        assert!(eval_str("rgb_to_hex(256, 0, 0)").is_err());
+        assert!(eval_str("rgb_to_hex(0, -1, 0)").is_err());
+        assert!(eval_str("rgb_to_hex(0, 0, \"blue\")").is_err());
+        assert_eq!(eval_str("rgb_to_hsl(\"#0f0\")").unwrap().as_json_value().unwrap(), serde_json::json!([120, 1, 0.5]));
+    }
+
+    #[test]
+    fn test_eval_typed() {
+        let vars = maplit::hashmap! { "x".to_string() => DynVal::from(2) };
+        let parse = |s: &str| crate::parse_string(0, s).unwrap();
+        assert!(parse("x == 2").eval_to_bool(&vars).unwrap());
+        assert_eq!(parse("x * 1.5").eval_to_f64(&vars).unwrap(), 3.0);
+        assert_eq!(parse("\"a\" + x").eval_to_string(&vars).unwrap(), "a2");
+
+        let err = parse("\"a\" + x").eval_to_f64(&vars).unwrap_err();
+        assert!(matches!(err, EvalError::ConversionError(_)));
+        assert_eq!(err.span(), Some(Span(0, 7, 0)));
+        assert!(parse("x").eval_to_bool(&vars).is_err());
+    }
+
+    #[test]
+    fn test_switch() {
+        assert_eq!(eval_str("switch(2, 1, \"one\", 2.0, \"two\", \"many\")").unwrap().0, "two");
+        assert_eq!(eval_str("switch(\"b\", \"a\", 1, \"b\", 2, 0)").unwrap().0, "2");
+        assert_eq!(eval_str("switch(5, 1, \"one\", 2, \"two\", \"many\")").unwrap().0, "many");
+        assert!(eval_str("switch(5, 1, \"one\", 2, \"two\")").is_err());
+        assert!(eval_str("switch(5, \"default\")").is_err());
+    }
+
+    #[test]
+    fn test_choose() {
+        assert_eq!(eval_str("choose(0, \"low\", \"mid\", \"high\")").unwrap().0, "low");
+        assert_eq!(eval_str("choose(2, \"low\", \"mid\", \"high\")").unwrap().0, "high");
+        assert_eq!(eval_str("choose(1 + 0, 10, 20)").unwrap().0, "20");
+        assert!(eval_str("choose(3, \"low\", \"mid\", \"high\")").unwrap().is_empty());
+        assert!(eval_str("choose(-1, \"low\", \"mid\", \"high\")").unwrap().is_empty());
+        assert!(eval_str("choose(1.5, \"low\", \"mid\")").is_err());
+        assert!(eval_str("choose(0)").is_err());
+    }
+
+    #[test]
+    fn test_base_conversion() {
+        assert_eq!(eval_str("to_hex(255)").unwrap().0, "ff");
+        assert_eq!(eval_str("to_hex(-255)").unwrap().0, "-ff");
+        assert_eq!(eval_str("to_binary(5)").unwrap().0, "101");
+        assert_eq!(eval_str("to_octal(8)").unwrap().0, "10");
+        assert!(eval_str("to_hex(1.5)").is_err());
+        assert_eq!(eval_str("to_hex(4294967296)").unwrap().0, "100000000");
+        assert_eq!(eval_str("to_binary(-4294967296)").unwrap().0, format!("-1{}", "0".repeat(32)));
+        assert_eq!(eval_str("from_base(to_octal(9007199254740993), 8)").unwrap().0, "9007199254740993");
+        assert_eq!(eval_str("from_base(\"ff\", 16)").unwrap(), DynVal::from(255));
+        assert_eq!(eval_str("from_base(\"-101\", 2)").unwrap(), DynVal::from(-5));
+        assert!(eval_str("from_base(\"12\", 2)").is_err());
+        assert!(eval_str("from_base(\"12\", 37)").is_err());
+    }
+
+    #[test]
+    fn test_eval_with_default() {
+        let vars = maplit::hashmap! { "x".to_string() => DynVal::from("abc") };
+        let parse = |s: &str| crate::parse_string(0, s).unwrap();
+        assert_eq!(parse("x * 2").eval_with_default(&vars, DynVal::from("fallback")).0, "fallback");
+        assert_eq!(parse("x + 2").eval_with_default(&vars, DynVal::from("fallback")).0, "abc2");
+
+        let mut logged = None;
+        let value = parse("x * 2").eval_or_else(&vars, |err| {
+            logged = Some(err.to_string());
+            DynVal::from(0)
+        });
+        assert_eq!(value, DynVal::from(0));
+        assert!(logged.unwrap().contains("f64"));
+    }
+
+    #[test]
+    fn test_percent() {
+        assert_eq!(eval_str("percent(1, 4)").unwrap().0, "25");
+        assert_eq!(eval_str("percent(1, 8)").unwrap().0, "12.5");
+        assert_eq!(eval_str("percent(3, 0)").unwrap().0, "0");
+        assert_eq!(eval_str("percent_of(25, 200)").unwrap().0, "50");
+        assert_eq!(eval_str("percent_of(50, 0)").unwrap().0, "0");
+        assert!(eval_str("percent(\"a\", 2)").is_err());
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn test_format_time() {
+        assert_eq!(eval_str("format_time_utc(1234567890, \"%Y-%m-%d %H:%M:%S\")").unwrap().0, "2009-02-13 23:31:30");
+        assert_eq!(eval_str("format_time_utc(0, \"%a %e %b\")").unwrap().0, "Thu  1 Jan");
+        assert!(eval_str("format_time(1234567890, \"%Y\")").unwrap().0.starts_with("2009"));
+        let err = eval_str("format_time_utc(0, \"%Q\")").unwrap_err();
+        assert!(matches!(err, EvalError::Spanned(_, box EvalError::InvalidTimeFormat(_))));
+        assert!(eval_str("format_time(0, \"%Q\")").is_err());
+    }
+
+    #[test]
+    fn test_array_concat() {
+        let json = |s: &str| eval_str(s).unwrap().as_json_value().unwrap();
+        assert_eq!(json("array_concat(\"[1, 2]\", \"[]\", \"[3, [4]]\")"), serde_json::json!([1, 2, 3, [4]]));
+        assert_eq!(json("array_concat(\"[]\")"), serde_json::json!([]));
+        assert_eq!(json("array_concat(\"[]\", \"[]\")"), serde_json::json!([]));
+        let err = eval_str("array_concat(\"[1]\", \"{}\")").unwrap_err();
+        assert!(err.to_string().contains("Argument 2"), "{}", err);
+        assert!(eval_str("array_concat()").is_err());
+    }
+
+    #[test]
+    fn test_flatten() {
+        let call = |name: &str, array: &str| call_expr_function(name, vec![DynVal::from(array)]);
+        let json = |name: &str, array: &str| call(name, array).unwrap().as_json_value().unwrap();
+        assert_eq!(json("flatten", "[[1, 2], [3], []]"), serde_json::json!([1, 2, 3]));
+        assert_eq!(json("flatten", "[[1, [2, [3]]], [4]]"), serde_json::json!([1, [2, [3]], 4]));
+        assert_eq!(json("flatten_deep", "[[1, [2, [3]]], [4]]"), serde_json::json!([1, 2, 3, 4]));
+        assert_eq!(json("flatten_deep", "[[[[]]], [[1]]]"), serde_json::json!([1]));
+        assert_eq!(json("flatten", r#"[1, ["a", "b"], {"k": [2]}, null]"#), serde_json::json!([1, "a", "b", {"k": [2]}, null]));
+        assert_eq!(json("flatten_deep", r#"[1, ["a", ["b"]], true]"#), serde_json::json!([1, "a", "b", true]));
+        assert_eq!(json("flatten", "[]"), serde_json::json!([]));
+        assert!(matches!(call("flatten", "{}"), Err(EvalError::WrongArgType(..))));
+        assert!(matches!(call("flatten_deep", "not an array"), Err(EvalError::WrongArgType(..))));
+    }
+
+    #[test]
+    fn test_pairs() {
+        let call = |name: &str, value: &str| call_expr_function(name, vec![DynVal::from(value)]);
+        let json = |name: &str, value: &str| call(name, value).unwrap().as_json_value().unwrap();
+        let object = r#"{"a": 1, "b": [true], "c": {"d": null}}"#;
+        assert_eq!(json("pairs", object), serde_json::json!([["a", 1], ["b", [true]], ["c", {"d": null}]]));
+        assert_eq!(json("pairs", "{}"), serde_json::json!([]));
+        let round_trip = call("object_from_pairs", &call("pairs", object).unwrap().0).unwrap();
+        assert_eq!(round_trip.as_json_value().unwrap(), serde_json::from_str::<serde_json::Value>(object).unwrap());
+
+        assert_eq!(json("object_from_pairs", r#"[["a", 1], ["b", 2], ["a", 3]]"#), serde_json::json!({"a": 3, "b": 2}));
+        assert_eq!(json("object_from_pairs", r#"[[1, "one"], [true, "yes"]]"#), serde_json::json!({"1": "one", "true": "yes"}));
+        assert_eq!(json("object_from_pairs", "[]"), serde_json::json!({}));
+        let err = call("object_from_pairs", r#"[["a", 1], ["b"]]"#);
+        assert!(matches!(err, Err(EvalError::WrongElementType(_, 1, _))));
+        assert!(matches!(call("object_from_pairs", r#"["a"]"#), Err(EvalError::WrongElementType(_, 0, _))));
+        assert!(matches!(call("pairs", "[1, 2]"), Err(EvalError::WrongArgType(..))));
+    }
+
+    #[test]
+    fn test_object_merge() {
+        let vars = maplit::hashmap! {
+            "a".to_string() => DynVal::from(r#"{"x": 1, "nested": {"a": 1, "b": 2}}"#),
+            "b".to_string() => DynVal::from(r#"{"y": 2, "nested": {"b": 3}}"#),
+        };
+        let json = |s: &str| crate::parse_string(0, s).unwrap().eval(&vars).unwrap().as_json_value().unwrap();
+        assert_eq!(json("object_merge(a, b)"), serde_json::json!({"x": 1, "y": 2, "nested": {"b": 3}}));
+        assert_eq!(json("object_merge(b, a)"), serde_json::json!({"x": 1, "y": 2, "nested": {"a": 1, "b": 2}}));
+        assert_eq!(json("object_merge_deep(a, b)"), serde_json::json!({"x": 1, "y": 2, "nested": {"a": 1, "b": 3}}));
+        assert_eq!(json("object_merge(a)"), json("a"));
+        assert!(crate::parse_string(0, "object_merge(a, \"[1]\")").unwrap().eval(&vars).is_err());
+    }
+
+    #[test]
+    fn test_inline_function() {
+        let parse = |s: &str| crate::parse_string(0, s).unwrap();
+        let body = parse("x * 2");
+        let params = vec!["x".to_string()];
+
+        let inlined = parse("double(a + 1) + double(double(b))").inline_function("double", &params, &body);
+        assert_eq!(inlined.to_string(), "(((a + \"1\") * \"2\") + ((b * \"2\") * \"2\"))");
+        assert!(inlined.free_functions().is_empty());
+
+        let untouched = parse("double(a, b)").inline_function("double", &params, &body);
+        assert_eq!(untouched.to_string(), "double(a, b)");
+        assert_eq!(untouched.free_functions(), vec!["double"]);
+    }
+
+    #[test]
+    fn test_contains_function() {
+        let expr = crate::parse_string(0, "x > 1 ? round(random() * 10, 0) : !obj[env(\"KEY\", 0)]").unwrap();
+        assert!(expr.contains_function("round"));
+        assert!(expr.contains_function("random"));
+        assert!(expr.contains_function("env"));
+        assert!(!expr.contains_function("rand"));
+        assert!(!expr.contains_function("x"));
+        assert!(!crate::parse_string(0, "\"random()\"").unwrap().contains_function("random"));
+    }
+
+    #[test]
+    fn test_literal_values() {
+        let expr = crate::parse_string(0, "x > 1 ? round(\"token-123\" + 2.5, 0) : !obj.key[env(\"SECRET\", -1)]").unwrap();
+        let values = expr.literal_values().into_iter().map(|x| x.0.as_str()).collect_vec();
+        assert_eq!(values, vec!["1", "token-123", "2.5", "0", "key", "SECRET", "-1"]);
+        assert!(crate::parse_string(0, "a + b").unwrap().literal_values().is_empty());
+    }
+
+    #[test]
+    fn test_type_hint() {
+        let hint = |s: &str| crate::parse_string(0, s).unwrap().type_hint();
+        assert_eq!(hint("x > 2 || y == \"a\""), Some(SimplType::Bool));
+        assert_eq!(hint("!enabled"), Some(SimplType::Bool));
+        assert_eq!(hint("(1 + 2) * x % 3"), Some(SimplType::Number));
+        assert_eq!(hint("1 + 2.5"), Some(SimplType::Number));
+        assert_eq!(hint("\"Volume: \" + volume + \"%\""), Some(SimplType::String));
+        assert_eq!(hint("\"[1, 2]\""), Some(SimplType::Json));
+        assert_eq!(hint("\"[not json\""), Some(SimplType::String));
+        assert_eq!(hint("x + 1"), Some(SimplType::Unknown));
+        assert_eq!(hint("muted ? \"off\" : \"on\""), Some(SimplType::String));
+        assert_eq!(hint("muted ? 0 : \"on\""), Some(SimplType::Unknown));
+        assert_eq!(hint("obj.field"), Some(SimplType::Unknown));
+        assert_eq!(hint("round(x, 2)"), Some(SimplType::Number));
+        assert_eq!(hint("as_fraction(\"50%\")"), Some(SimplType::Number));
+        assert_eq!(hint("slug(title)"), Some(SimplType::String));
+        assert_eq!(hint("to_chars(title)"), Some(SimplType::Json));
+        assert_eq!(hint("flatten(items)"), Some(SimplType::Json));
+        assert_eq!(hint("not_a_builtin(1)"), None);
+        assert_eq!(hint("1 + round(not_a_builtin(1), 0)"), None);
+    }
+
+    #[test]
+    fn test_bind() {
+        let expr = crate::parse_string(0, "greeting + \", \" + name + (name == \"\" ? \"?\" : \"!\")").unwrap();
+        let bound = expr.bind("name", DynVal::from("world"));
+        assert_eq!(bound.var_refs().iter().map(|x| x.to_string()).collect_vec(), vec!["greeting"]);
+        let vars = maplit::hashmap! { "greeting".to_string() => DynVal::from("hello") };
+        assert_eq!(bound.eval(&vars).unwrap().0, "hello, world!");
+        assert_eq!(bound.bind("greeting", DynVal::from("hi")).eval_no_vars().unwrap().0, "hi, world!");
+    }
+
+    #[test]
+    fn test_elvis_empty() {
+        let vars = maplit::hashmap! {
+            "empty".to_string() => DynVal::empty(),
+            "null".to_string() => DynVal::from(&serde_json::Value::Null),
+        };
+        let eval = |s: &str| crate::parse_string(0, s).unwrap().eval(&vars).unwrap().0;
+        assert!(DynVal::empty().is_empty());
+        assert!(!DynVal::from(&serde_json::Value::Null).is_empty());
+        assert_eq!(eval("empty ?: \"fallback\""), "fallback");
+        assert_eq!(eval("null ?: \"fallback\""), "null");
+        assert_eq!(eval("\"value\" ?: \"fallback\""), "value");
+    }
+
+    #[test]
+    fn test_wrap_text() {
+        let wrap = |s: &str, width: i32| call_expr_function("wrap_text", vec![DynVal::from(s), DynVal::from(width)]).unwrap().0;
+        assert_eq!(wrap("the quick brown fox jumps over the lazy dog", 10), "the quick\nbrown fox\njumps over\nthe lazy\ndog");
+        assert_eq!(wrap("a supercalifragilistic word", 8), "a\nsupercal\nifragili\nstic\nword");
+        assert_eq!(wrap("first line\nsecond line here", 11), "first line\nsecond line\nhere");
+        assert_eq!(wrap("日本語 テキスト", 6), "日本語\nテキス\nト");
+    }
+
+    #[test]
+    fn test_is_empty_is_blank() {
+        let call = |name: &str, s: &str| call_expr_function(name, vec![DynVal::from(s)]).unwrap().as_bool().unwrap();
+        assert!(call("is_empty", ""));
+        assert!(!call("is_empty", " "));
+        assert!(!call("is_empty", "text"));
+        assert!(call("is_blank", ""));
+        assert!(call("is_blank", " \t\n\r "));
+        assert!(call("is_blank", "\u{a0}\u{3000}\u{2003}"));
+        assert!(!call("is_blank", "  x  "));
+        assert!(!call("is_blank", "\u{3000}文\u{3000}"));
+        assert_eq!(eval_str("is_blank(\"   \") && !is_empty(\"   \")").unwrap().0, "true");
+    }
+
+    #[test]
+    fn test_first_last_line() {
+        let call = |name: &str, s: &str| call_expr_function(name, vec![DynVal::from(s)]).unwrap().0;
+        assert_eq!(call("first_line", "single"), "single");
+        assert_eq!(call("last_line", "single"), "single");
+        assert_eq!(call("first_line", "one\ntwo\nthree"), "one");
+        assert_eq!(call("last_line", "one\ntwo\nthree"), "three");
+        assert_eq!(call("first_line", "one\r\ntwo\r\n"), "one");
+        assert_eq!(call("last_line", "one\r\ntwo\r\n"), "two");
+        assert_eq!(call("last_line", "one\ntwo\n\n"), "");
+        assert_eq!(call("first_line", ""), "");
+        assert_eq!(call("last_line", ""), "");
+    }
+
+    #[test]
+    fn test_clamp_text_lines() {
+        let clamp = |s: &str, n: i32| call_expr_function("clamp_text_lines", vec![DynVal::from(s), DynVal::from(n)]).unwrap().0;
+        assert_eq!(clamp("a\nb", 3), "a\nb");
+        assert_eq!(clamp("a\nb\nc", 3), "a\nb\nc");
+        assert_eq!(clamp("a\nb\nc\n", 3), "a\nb\nc\n");
+        assert_eq!(clamp("a\nb\nc\nd", 3), "a\nb\nc\n…");
+        assert_eq!(clamp("a\r\nb\r\nc", 2), "a\nb\n…");
+        assert_eq!(clamp("a\nb", 0), "");
+        assert_eq!(clamp("a\nb", -1), "");
+    }
+
+    #[test]
+    fn test_dedent_indent() {
+        let call = |name: &str, args: Vec<DynVal>| call_expr_function(name, args).unwrap().0;
+        assert_eq!(call("dedent", vec![DynVal::from("    a\n      b\n\n    c")]), "a\n  b\n\nc");
+        assert_eq!(call("dedent", vec![DynVal::from("\t\ta\n\t  b\n  \n\tc")]), "\ta\n  b\n\nc");
+        assert_eq!(call("dedent", vec![DynVal::from("\ta\n    b")]), "\ta\n    b");
+        assert_eq!(call("dedent", vec![DynVal::from("no indent")]), "no indent");
+        assert_eq!(call("indent", vec![DynVal::from("a\n\n\tb"), DynVal::from(2)]), "  a\n\n  \tb");
+    }
+
+    #[test]
+    fn test_slug() {
+        let slug = |s: &str| call_expr_function("slug", vec![DynVal::from(s)]).unwrap().0;
+        assert_eq!(slug("Hello World"), "hello-world");
+        assert_eq!(slug("  What's up?!  Not much... "), "what-s-up-not-much");
+        assert_eq!(slug("--already-a-slug--"), "already-a-slug");
+        assert_eq!(slug("snake_case and\ttabs\n"), "snake-case-and-tabs");
+        assert_eq!(slug("Version 2.0"), "version-2-0");
+        assert_eq!(slug("Über Café"), "über-café");
+        assert_eq!(slug("!!!"), "");
+    }
+
+    #[test]
+    fn test_replace_map() {
+        let replace_map = |s: &str, map: &str| call_expr_function("replace_map", vec![DynVal::from(s), DynVal::from(map)]);
+        assert_eq!(replace_map("Hello, name!", r#"{"name": "World"}"#).unwrap().0, "Hello, World!");
+        assert_eq!(replace_map("a ab abc", r#"{"a": "1", "ab": "2", "abc": "3"}"#).unwrap().0, "1 2 3");
+        assert_eq!(replace_map("ab", r#"{"a": "b", "b": "c"}"#).unwrap().0, "bc");
+        assert_eq!(replace_map("x y", r#"{"x": "y", "y": "x"}"#).unwrap().0, "y x");
+        assert_eq!(replace_map("1 < 2", r#"{"<": "&lt;", "": "oops", "2": 3}"#).unwrap().0, "1 &lt; 3");
+        assert_eq!(replace_map("äöü", r#"{"ö": "oe"}"#).unwrap().0, "äoeü");
+        assert!(matches!(replace_map("abc", "[1, 2]"), Err(EvalError::WrongArgType(_, 2, _))));
+    }
+
+    #[test]
+    fn test_to_from_chars() {
+        let to_chars = |s: &str| call_expr_function("to_chars", vec![DynVal::from(s)]).unwrap();
+        assert_eq!(to_chars("abc").as_json_value().unwrap(), serde_json::json!(["a", "b", "c"]));
+        assert_eq!(to_chars("日本語").as_json_value().unwrap(), serde_json::json!(["日", "本", "語"]));
+        assert_eq!(to_chars("👍🏽!🇩🇪").as_json_value().unwrap(), serde_json::json!(["👍🏽", "!", "🇩🇪"]));
+        assert_eq!(to_chars("").as_json_value().unwrap(), serde_json::json!([]));
+        for input in ["abc", "日本語", "👨‍👩‍👧 family", ""].iter() {
+            let round_tripped = call_expr_function("from_chars", vec![to_chars(input)]).unwrap();
+            assert_eq!(round_tripped.0, *input);
+        }
+        assert_eq!(call_expr_function("from_chars", vec![DynVal::from(r#"[1, "a", 2]"#)]).unwrap().0, "1a2");
+        assert!(call_expr_function("from_chars", vec![DynVal::from("abc")]).is_err());
+    }
+
+    #[test]
+    fn test_reverse() {
+        let reverse = |s: &str| call_expr_function("reverse", vec![DynVal::from(s)]).unwrap().0;
+        assert_eq!(reverse("hello"), "olleh");
+        assert_eq!(reverse("äöü日本"), "本日üöä");
+        assert_eq!(reverse("cafe\u{301}!"), "!e\u{301}fac");
+        assert_eq!(reverse("[1, \"a\", [2, 3]]"), r#"[[2,3],"a",1]"#);
+        assert_eq!(reverse("123"), "321");
+    }
+
+    #[test]
+    fn test_unique() {
+        let unique = |s: &str| call_expr_function("unique", vec![DynVal::from(s)]).map(|x| x.as_json_value().unwrap());
+        assert_eq!(unique(r#"[1, "a", 2, "a", 1.0, "1"]"#).unwrap(), serde_json::json!([1, "a", 2]));
+        assert_eq!(unique(r#"[{"a": [1]}, [1, 2], {"a": [1]}, [1, 2]]"#).unwrap(), serde_json::json!([{"a": [1]}, [1, 2]]));
+        assert_eq!(unique("[]").unwrap(), serde_json::json!([]));
+        assert!(unique("abc").is_err());
+    }
+
+    #[test]
+    fn test_clamp_wrap_index() {
+        let call = |name: &str, array: &str, i: i32| call_expr_function(name, vec![DynVal::from(array), DynVal::from(i)]);
+        let array = "[\"a\", \"b\", \"c\"]";
+        assert_eq!(call("clamp_index", array, 1).unwrap().0, "1");
+        assert_eq!(call("clamp_index", array, -4).unwrap().0, "0");
+        assert_eq!(call("clamp_index", array, 3).unwrap().0, "2");
+        assert_eq!(call("clamp_index", array, 100).unwrap().0, "2");
+        assert_eq!(call("wrap_index", array, 1).unwrap().0, "1");
+        assert_eq!(call("wrap_index", array, -1).unwrap().0, "2");
+        assert_eq!(call("wrap_index", array, -4).unwrap().0, "2");
+        assert_eq!(call("wrap_index", array, 3).unwrap().0, "0");
+        assert_eq!(call("wrap_index", array, 7).unwrap().0, "1");
+        assert!(matches!(call("clamp_index", "[]", 0), Err(EvalError::IndexOutOfRange(0, 0))));
+        assert!(matches!(call("wrap_index", "[]", 2), Err(EvalError::IndexOutOfRange(2, 0))));
+        assert!(matches!(call("wrap_index", "abc", 0), Err(EvalError::WrongArgType(..))));
+    }
+
+    #[test]
+    fn test_slice() {
+        let vars = maplit::hashmap! { "arr".to_string() => DynVal::from("[0, 1, 2, 3, 4]") };
+        let json = |s: &str| crate::parse_string(0, s).unwrap().eval(&vars).unwrap().as_json_value().unwrap();
+        assert_eq!(json("slice(arr, 1, 3)"), serde_json::json!([1, 2]));
+        assert_eq!(json("slice(arr, 2)"), serde_json::json!([2, 3, 4]));
+        assert_eq!(json("slice(arr, -2)"), serde_json::json!([3, 4]));
+        assert_eq!(json("slice(arr, 1, -1)"), serde_json::json!([1, 2, 3]));
+        assert_eq!(json("slice(arr, -10, 10)"), serde_json::json!([0, 1, 2, 3, 4]));
+        assert_eq!(json("slice(arr, 3, 1)"), serde_json::json!([]));
+        assert!(crate::parse_string(0, "slice(arr)").unwrap().eval(&vars).is_err());
+    }
+
+    #[test]
+    fn test_durations() {
+        assert_eq!(eval_str("humanize_duration(250)").unwrap().0, "250ms");
+        assert_eq!(eval_str("humanize_duration(0)").unwrap().0, "0s");
+        assert_eq!(eval_str("humanize_duration(3723999)").unwrap().0, "1h 2m 3s");
+        assert_eq!(eval_str("humanize_duration(90000000)").unwrap().0, "25h");
+        assert_eq!(eval_str("humanize_duration(60500)").unwrap().0, "1m");
+        assert_eq!(eval_str("parse_duration(\"1h 2m 3s\")").unwrap(), DynVal::from(3723000));
+        assert_eq!(eval_str("parse_duration(\"250ms\")").unwrap(), DynVal::from(250));
+        assert_eq!(eval_str("parse_duration(humanize_duration(3723000))").unwrap(), DynVal::from(3723000));
+        assert!(eval_str("parse_duration(\"1x\")").is_err());
+    }
+
+    #[test]
+    fn test_lenient_indexing() {
+        let vars = maplit::hashmap! { "num".to_string() => DynVal::from(5), "arr".to_string() => DynVal::from("[1, 2]") };
+        let lenient = EvalOptions::new().with_lenient_indexing(true);
+        let parse = |s: &str| crate::parse_string(0, s).unwrap();
+        assert!(parse("num[0]").eval(&vars).is_err());
+        assert!(parse("num[0]").eval_with_options(&vars, &EvalOptions::default()).is_err());
+        assert_eq!(parse("num[0]").eval_with_options(&vars, &lenient).unwrap().0, "");
+        assert_eq!(parse("\"not json\".foo").eval_with_options(&vars, &lenient).unwrap().0, "");
+        assert_eq!(parse("arr[1]").eval_with_options(&vars, &lenient).unwrap(), DynVal::from(2));
+    }
+
+    #[test]
+    fn test_strict_indexing() {
+        let vars = maplit::hashmap! { "arr".to_string() => DynVal::from("[1,2,3]") };
+        let strict = EvalOptions::new().with_strict_indexing(true);
+        let parse = |s: &str| crate::parse_string(0, s).unwrap();
+        assert_eq!(parse("arr[5]").eval(&vars).unwrap().0, "null");
+        let err = parse("arr[5]").eval_with_options(&vars, &strict).unwrap_err();
+        assert!(matches!(err, EvalError::Spanned(_, box EvalError::IndexOutOfRange(5, 3))));
+        assert!(parse("arr[-1]").eval_with_options(&vars, &strict).is_err());
+        assert_eq!(parse("arr[2]").eval_with_options(&vars, &strict).unwrap(), DynVal::from(3));
+        assert_eq!(parse("arr?[5]").eval_with_options(&vars, &strict).unwrap().0, "");
+    }
+
+    #[test]
+    fn test_non_finite_arithmetic() {
+        let err = |s: &str| match eval_str(s).unwrap_err() {
+            EvalError::Spanned(_, box err) => err,
+            err => err,
+        };
+        assert!(matches!(err("1 / 0"), EvalError::DivisionByZero));
+        assert!(matches!(err("0 / 0"), EvalError::DivisionByZero));
+        assert!(matches!(err("5 % 0"), EvalError::DivisionByZero));
+        assert!(matches!(err("\"inf\" - \"inf\""), EvalError::NonFiniteResult(x) if x.is_nan()));
+        assert!(matches!(err("\"NaN\" * 2"), EvalError::NonFiniteResult(_)));
+        assert!(matches!(err("lerp(0, \"inf\", 0.5)"), EvalError::NonFiniteResult(_)));
+        assert!(matches!(err("clamp01(\"NaN\")"), EvalError::NonFiniteResult(x) if x.is_nan()));
+        assert!(matches!(err("trunc(\"inf\")"), EvalError::NonFiniteResult(_)));
+        assert!(matches!(err("fract(\"-inf\")"), EvalError::NonFiniteResult(_)));
+        assert!(matches!(err("percent(\"inf\", 4)"), EvalError::NonFiniteResult(_)));
+        assert!(matches!(err("as_fraction(\"NaN%\")"), EvalError::NonFiniteResult(_)));
+        assert_eq!(eval_str("1 / 4").unwrap(), DynVal::from(0.25));
+    }
+
+    #[test]
+    fn test_float_artifacts() {
+        assert_eq!(eval_str("0.1 + 0.2").unwrap().0, "0.3");
+        assert_eq!(eval_str("0.1 + 0.2 == 0.3").unwrap().0, "true");
+        // values are not rounded beyond the error of the arithmetic, so that it does not compound across operations.
+        assert_eq!(eval_str("1 / 3 * 3").unwrap().0, "1");
+        assert_eq!(eval_str("1 / 3 * 3 == 1").unwrap().0, "true");
+        assert_eq!(eval_str("2 / 3 + 1 / 3").unwrap().0, "1");
+        assert_eq!(eval_str("1 / 7 * 7").unwrap().0, "1");
+    }
+
+    #[test]
+    fn test_to_fixed() {
+        assert_eq!(eval_str("to_fixed(1.5, 2)").unwrap().0, "1.50");
+        assert_eq!(eval_str("round(2.345, 1)").unwrap().0, "2.3");
+        assert_eq!(eval_str("to_fixed(1.5, 2) + 1").unwrap().0, "2.5");
+        assert_eq!(eval_str("to_fixed(1.5, 2) == 1.5").unwrap().0, "true");
+        assert_eq!(eval_str("round_num(1.5, 2)").unwrap().0, "1.5");
+        assert_eq!(eval_str("round_num(1.456, 2)").unwrap().0, "1.46");
+        assert_eq!(eval_str("round_num(1234, -2)").unwrap().0, "1200");
+    }
+
+    #[test]
+    fn test_round_even() {
+        assert_eq!(eval_str("round_even(0.5, 0)").unwrap().0, "0");
+        assert_eq!(eval_str("round_even(1.5, 0)").unwrap().0, "2");
+        assert_eq!(eval_str("round_even(2.5, 0)").unwrap().0, "2");
+        assert_eq!(eval_str("round_even(-0.5, 0)").unwrap().0, "0");
+        assert_eq!(eval_str("round_even(-2.5, 0)").unwrap().0, "-2");
+        assert_eq!(eval_str("round_even(2.6, 0)").unwrap().0, "3");
+        assert_eq!(eval_str("round_even(0.125, 2)").unwrap().0, "0.12");
+        assert_eq!(eval_str("round_even(0.375, 2)").unwrap().0, "0.38");
+        assert_eq!(eval_str("round_even(1250, -2)").unwrap().0, "1200");
+
+        assert_eq!(eval_str("round_num(0.5, 0)").unwrap().0, "1");
+        assert_eq!(eval_str("round_num(1.5, 0)").unwrap().0, "2");
+        assert_eq!(eval_str("round_num(2.5, 0)").unwrap().0, "3");
+    }
+
+    #[test]
+    fn test_round_to_multiple() {
+        assert_eq!(eval_str("round_to_multiple(12, 5)").unwrap().0, "10");
+        assert_eq!(eval_str("round_to_multiple(13, 5)").unwrap().0, "15");
+        assert_eq!(eval_str("round_to_multiple(-13, 5)").unwrap().0, "-15");
+        assert_eq!(eval_str("round_to_multiple(1.3, 0.25)").unwrap().0, "1.25");
+        assert_eq!(eval_str("round_to_multiple(0.7, 0.1)").unwrap().0, "0.7");
+        let err = eval_str("round_to_multiple(3, 0)").unwrap_err();
+        assert!(matches!(err, EvalError::Spanned(_, box EvalError::DivisionByZero)));
+    }
+
+    #[test]
+    fn test_debug() {
+        let vars = maplit::hashmap! { "x".to_string() => DynVal::from(3) };
+        let expr = crate::parse_string(0, "debug(x * 2, \"doubled\") + 1").unwrap();
+        let calls = std::cell::RefCell::new(Vec::new());
+        let callback = |label: &str, value: &DynVal| calls.borrow_mut().push((label.to_string(), value.0.clone()));
+        let options = EvalOptions::new().with_debug_callback(&callback);
+        assert_eq!(expr.eval_with_options(&vars, &options).unwrap(), DynVal::from(7));
+        assert_eq!(calls.into_inner(), vec![("doubled".to_string(), "6".to_string())]);
+        assert_eq!(expr.eval(&vars).unwrap(), DynVal::from(7));
+    }
+
+    #[test]
+    fn test_sort_by_key() {
+        let vars = maplit::hashmap! {
+            "people".to_string() => DynVal::from(r#"[
+                {"name": "b", "info": {"age": 30}},
+                {"name": "a", "info": {"age": 4}},
+                {"name": "c", "info": {"age": 30}}
+            ]"#),
+        };
+        let eval = |s: &str| crate::parse_string(0, s).unwrap().eval(&vars);
+        let sorted = eval("sort_by_key(people, \"$item.info.age\")").unwrap().as_json_value().unwrap();
+        let names = sorted.as_array().unwrap().iter().map(|x| x["name"].as_str().unwrap()).collect_vec();
+        assert_eq!(names, vec!["a", "b", "c"]);
+
+        let sorted = eval("sort_by_key(people, \"$item.name\")").unwrap().as_json_value().unwrap();
+        assert_eq!(sorted[0]["name"], "a");
+        assert_eq!(eval("min_by_key(people, \"$item.info.age\").name").unwrap().0, "a");
+        assert_eq!(eval("sort_by_key(\"[10, 9, 100]\", \"$item\")").unwrap().0, "[9,10,100]");
+
+        let err = eval("sort_by_key(people, \"$item +\")").unwrap_err();
+        assert!(err.to_string().contains("$item +"), "{}", err);
+        assert!(eval("sort_by_key(people, \"$item.name * 2\")").is_err());
+    }
+
+    #[test]
+    fn test_group_by() {
+        let vars = maplit::hashmap! {
+            "items".to_string() => DynVal::from(r#"[
+                {"name": "a", "kind": "fruit"},
+                {"name": "b", "kind": "vegetable"},
+                {"name": "c", "kind": "fruit"}
+            ]"#),
+        };
+        let eval = |s: &str| crate::parse_string(0, s).unwrap().eval(&vars);
+        assert_eq!(
+            eval("group_by(items, \"$item.kind\")").unwrap().as_json_value().unwrap(),
+            serde_json::json!({
+                "fruit": [{"name": "a", "kind": "fruit"}, {"name": "c", "kind": "fruit"}],
+                "vegetable": [{"name": "b", "kind": "vegetable"}],
+            })
+        );
+        assert_eq!(
+            eval("group_by(\"[1, 2, 3, 4, 5]\", \"$item % 2 == 0\")").unwrap().as_json_value().unwrap(),
+            serde_json::json!({"false": [1, 3, 5], "true": [2, 4]})
+        );
+        assert!(eval("group_by(items, \"$item.kind * 2\")").is_err());
+    }
+
+    #[test]
+    fn test_count() {
+        let items = r#"[{"name": "a", "done": true}, {"name": "b", "done": false}, {"name": "c", "done": true}]"#;
+        let count =
+            |array: &str, predicate: &str| call_expr_function("count", vec![DynVal::from(array), DynVal::from(predicate)]);
+        assert_eq!(count(items, "$item.done").unwrap().0, "2");
+        assert_eq!(count(items, "!$item.done && $item.name == \"b\"").unwrap().0, "1");
+        assert_eq!(count("[1, 2, 3, 4, 5]", "$item > 2").unwrap().0, "3");
+        assert_eq!(count("[1, 2, 3]", "$item > 5").unwrap().0, "0");
+        assert_eq!(count("[]", "$item").unwrap().0, "0");
+        assert!(matches!(count("[1]", "$item.x"), Err(EvalError::SubExpression(..))));
+        assert!(matches!(count("[1]", "$item +"), Err(EvalError::SubExpression(..))));
+        assert!(count("[1]", "$item").is_err());
+    }
+
+    #[test]
+    fn test_take_drop_while() {
+        let call = |name: &str, array: &str, predicate: &str| {
+            call_expr_function(name, vec![DynVal::from(array), DynVal::from(predicate)]).map(|x| x.0)
+        };
+        assert_eq!(call("take_while", "[1, 2, 5, 1]", "$item < 3").unwrap(), "[1,2]");
+        assert_eq!(call("drop_while", "[1, 2, 5, 1]", "$item < 3").unwrap(), "[5,1]");
+        assert_eq!(call("take_while", "[1, 2, 3]", "$item > 5").unwrap(), "[]");
+        assert_eq!(call("drop_while", "[1, 2, 3]", "$item > 5").unwrap(), "[1,2,3]");
+        assert_eq!(call("take_while", "[1, 2, 3]", "$item > 0").unwrap(), "[1,2,3]");
+        assert_eq!(call("drop_while", "[1, 2, 3]", "$item > 0").unwrap(), "[]");
+        assert_eq!(call("take_while", "[]", "$item").unwrap(), "[]");
+        let items = r#"[{"done": true}, {"done": false}, {"done": true}]"#;
+        assert_eq!(call("drop_while", items, "$item.done").unwrap(), r#"[{"done":false},{"done":true}]"#);
+        // the predicate is only evaluated until it first fails.
+        assert_eq!(call("take_while", "[1, 5, \"x\"]", "$item < 3").unwrap(), "[1]");
+        assert!(matches!(call("take_while", "[1]", "$item.x"), Err(EvalError::SubExpression(..))));
+        assert!(matches!(call("drop_while", "[1]", "$item +"), Err(EvalError::SubExpression(..))));
+        assert!(matches!(call("drop_while", "[1]", "$item"), Err(EvalError::ConversionError(_))));
+    }
+
+    #[test]
+    fn test_array_reductions() {
+        assert_eq!(eval_str("sum(\"[1, 2, 3.5]\")").unwrap().0, "6.5");
+        assert_eq!(eval_str("product(\"[2, 3, 4]\")").unwrap().0, "24");
+        assert_eq!(eval_str("average(\"[1, 2, 3, 4]\")").unwrap().0, "2.5");
+        assert_eq!(eval_str("sum(\"[]\")").unwrap().0, "0");
+        assert_eq!(eval_str("product(\"[]\")").unwrap().0, "1");
+        assert_eq!(eval_str("average(\"[]\")").unwrap().0, "0");
+        let err = eval_str("sum(\"[1, 2, true]\")").unwrap_err();
+        assert!(err.to_string().contains("Element 2"), "{}", err);
+    }
+
+    #[test]
+    fn test_safe_access() {
+        let vars = maplit::hashmap! {
+            "obj".to_string() => DynVal::from(r#"{"a": {"b": [1, 2]}, "n": null}"#),
+            "empty".to_string() => DynVal::empty(),
+        };
+        let eval = |s: &str| crate::parse_string(0, s).unwrap().eval(&vars).unwrap().0;
+        assert_eq!(eval("obj?.a?.b?[1]"), "2");
+        assert_eq!(eval("obj?[\"a\"]?.b"), "[1,2]");
+        assert_eq!(eval("obj?.a?.b?[5]"), "");
+        assert_eq!(eval("obj?.missing?.b?[0]"), "");
+        assert_eq!(eval("obj?.n?.x"), "");
+        assert_eq!(eval("empty?.x?.y ?: \"fallback\""), "fallback");
+        assert_eq!(eval("obj.a?.b?[\"not a number\"]"), "");
+        assert!(crate::parse_string(0, "obj.n.x").unwrap().eval(&vars).is_err());
+        assert_eq!(crate::parse_string(0, "obj?.a?[0]").unwrap().to_string(), "obj?[\"a\"]?[\"0\"]");
+    }
+
+    #[cfg(feature = "rand")]
+    #[test]
+    fn test_random() {
+        for _ in 0..1000 {
+            let x = eval_str("random()").unwrap().as_f64().unwrap();
+            assert!((0.0..1.0).contains(&x), "{}", x);
+            let n = eval_str("random_range(-2, 3)").unwrap().as_i32().unwrap();
+            assert!((-2..=3).contains(&n), "{}", n);
+            assert_eq!(eval_str("random_range(5, 5)").unwrap().as_i32().unwrap(), 5);
+            let n = eval_str("random_range(3, 1)").unwrap().as_i32().unwrap();
+            assert!((1..=3).contains(&n), "{}", n);
+        }
+        assert!(matches!(eval_str("random(1)"), Err(EvalError::Spanned(_, box EvalError::WrongArgCount(_)))));
+    }
+
+    #[test]
+    fn test_try() {
+        let vars = maplit::hashmap! {
+            "x".to_string() => DynVal::from(3),
+            "nested".to_string() => DynVal::from("try(\"x + 1\", 0) * 2"),
+            "recursive".to_string() => DynVal::from("try(recursive, 0) + 1"),
+        };
+        let eval = |s: &str| crate::parse_string(0, s).unwrap().eval(&vars).unwrap().0;
+        assert_eq!(eval("try(\"x * 2\", \"fallback\")"), "6");
+        assert_eq!(eval("try(\"x * \", \"fallback\")"), "fallback");
+        assert_eq!(eval("try(\"x / 0\", \"fallback\")"), "fallback");
+        assert_eq!(eval("try(\"missing\", \"fallback\")"), "fallback");
+        assert_eq!(eval("try(nested, 0)"), "8");
+        assert_eq!(eval("try(recursive, 0)"), "16");
+        assert!(crate::parse_string(0, "try(\"x\")").unwrap().eval(&vars).is_err());
+    }
+
+    #[test]
+    fn test_comparisons_agree() {
+        let values = ["10", "9", "9.0", "abc", "10a", "", "-1"];
+        for a in values.iter() {
+            for b in values.iter() {
+                let (a, b) = (DynVal::from(*a), DynVal::from(*b));
+                let vars = maplit::hashmap! { "a".to_string() => a.clone(), "b".to_string() => b.clone() };
+                let eval = |s: &str| crate::parse_string(0, s).unwrap().eval(&vars).unwrap().as_bool().unwrap();
+                let ordering = compare_values(&a, &b);
+                assert_eq!(eval("a == b"), ordering == std::cmp::Ordering::Equal, "{} == {}", a, b);
+                assert_eq!(eval("a < b"), ordering == std::cmp::Ordering::Less, "{} < {}", a, b);
+                assert_eq!(eval("a > b"), ordering == std::cmp::Ordering::Greater, "{} > {}", a, b);
+            }
+        }
+        assert_eq!(eval_str("\"10\" > \"9\"").unwrap().0, "true");
+        assert_eq!(eval_str("\"b\" > \"a\"").unwrap().0, "true");
+        let sorted = call_expr_function("sort_by_key", vec![DynVal::from(r#"[10, "b", 9, "a"]"#), DynVal::from("$item")]);
+        assert_eq!(sorted.unwrap().0, r#"[9,10,"a","b"]"#);
+    }
+
+    #[cfg(feature = "env")]
+    #[test]
+    fn test_env() {
+        std::env::set_var("SIMPLEXPR_TEST_ENV", "set");
+        assert_eq!(eval_str("env(\"SIMPLEXPR_TEST_ENV\", \"default\")").unwrap().0, "set");
+        std::env::remove_var("SIMPLEXPR_TEST_ENV");
+        assert_eq!(eval_str("env(\"SIMPLEXPR_TEST_ENV\", \"default\")").unwrap().0, "default");
+        assert!(eval_str("env(\"SIMPLEXPR_TEST_ENV\")").is_err());
+    }
+
+    #[test]
+    fn test_eval_bool() {
+        let vars = maplit::hashmap! {
+            "volume".to_string() => DynVal::from(30),
+            "muted".to_string() => DynVal::from(false),
+            "name".to_string() => DynVal::from("speaker"),
+        };
+        let inputs = [
+            "true",
+            "!muted && volume > 10",
+            "muted || volume < 10",
+            "name == \"speaker\" && !(volume != 30)",
+            "volume > 10 ? !muted : muted",
+            "name =~ \"^spe\" || false",
+        ];
+        for input in inputs.iter() {
+            let expr = crate::parse_string(0, input).unwrap();
+            assert_eq!(expr.eval_bool(&vars).unwrap(), expr.eval_to_bool(&vars).unwrap(), "{}", input);
+        }
+        let parse = |s: &str| crate::parse_string(0, s).unwrap();
+        assert!(!parse("muted && missing").eval_bool(&vars).unwrap());
+        assert!(parse("!muted || missing").eval_bool(&vars).unwrap());
+        assert!(parse("!muted && missing").eval_bool(&vars).is_err());
+        assert!(parse("name && true").eval_bool(&vars).is_err());
+        assert!(parse("volume").eval_bool(&vars).is_err());
+    }
+
+    #[test]
+    fn test_unknown_function_suggestion() {
+        assert_eq!(eval_str("rond(1.5, 1)").unwrap_err().to_string(), "Unknown function rond, did you mean round?");
+        let err = eval_str("rgb_to_hexx(1, 2, 3)").unwrap_err();
+        assert_eq!(err.to_string(), "Unknown function rgb_to_hexx, did you mean rgb_to_hex?");
+        assert_eq!(eval_str("frobnicate(1)").unwrap_err().to_string(), "Unknown function frobnicate");
+
+        let mut registry = FunctionRegistry::new();
+        registry.register("greet", |_| Ok(DynVal::from("hi")));
+        let err = crate::parse_string(0, "gret()").unwrap().eval_with_registry(&HashMap::new(), &registry).unwrap_err();
+        assert_eq!(err.to_string(), "Unknown function gret, did you mean greet?");
+    }
+
+    #[test]
+    fn test_builtin_functions_are_known() {
+        for name in BUILTIN_FUNCTIONS.iter() {
+            let err = eval_str(&format!("{}()", name)).err();
+            assert!(!matches!(err, Some(EvalError::Spanned(_, box EvalError::UnknownFunction(..)))), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("round", "round"), 0);
+        assert_eq!(edit_distance("rond", "round"), 1);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("äb", "ab"), 1);
     }
 }
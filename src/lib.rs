@@ -2,6 +2,8 @@
 #![feature(box_syntax)]
 #![feature(try_blocks)]
 pub mod ast;
+pub mod cache;
+pub mod compile;
 pub mod dynval;
 pub mod error;
 pub mod eval;
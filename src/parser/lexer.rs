@@ -21,6 +21,8 @@ pub enum Token {
 
     #[strum(serialize = ",")    ] #[token(",")    ] Comma,
     #[strum(serialize = "?")    ] #[token("?")    ] Question,
+    #[strum(serialize = "?.")   ] #[token("?.")   ] SafeDot,
+    #[strum(serialize = "?[")   ] #[token("?[")   ] SafeLBrack,
     #[strum(serialize = ":")    ] #[token(":")    ] Colon,
     #[strum(serialize = "(")    ] #[token("(")    ] LPren,
     #[strum(serialize = ")")    ] #[token(")")    ] RPren,
@@ -30,7 +32,8 @@ pub enum Token {
     #[strum(serialize = "true") ] #[token("true") ] True,
     #[strum(serialize = "false")] #[token("false")] False,
 
-    #[regex(r"[a-zA-Z_-]+", |x| x.slice().to_string())]
+    #[regex(r"-*[a-zA-Z_][a-zA-Z0-9_-]*", |x| x.slice().to_string())]
+    #[regex(r"\$[a-zA-Z_][a-zA-Z0-9_-]*", |x| x.slice().to_string())]
     Ident(String),
     #[regex(r"[+-]?(?:[0-9]+[.])?[0-9]+", |x| x.slice().to_string())]
     NumLit(String),
@@ -76,3 +79,37 @@ impl<'input> Iterator for Lexer<'input> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(s: &str) -> Vec<Result<Token, LexicalError>> {
+        Lexer::new(s).map(|x| x.map(|(_, token, _)| token)).collect()
+    }
+
+    fn ident(s: &str) -> Result<Token, LexicalError> {
+        Ok(Token::Ident(s.to_string()))
+    }
+
+    #[test]
+    fn test_identifiers() {
+        // digits are allowed after the first letter, so builtins like `clamp01` are a single identifier.
+        assert_eq!(tokens("clamp01 rgb_to_hsl2 $item2"), vec![ident("clamp01"), ident("rgb_to_hsl2"), ident("$item2")]);
+        assert_eq!(tokens("my-var _x x-1 a-"), vec![ident("my-var"), ident("_x"), ident("x-1"), ident("a-")]);
+        // leading hyphens are still part of the identifier, as before.
+        assert_eq!(tokens("-foo -_1"), vec![ident("-foo"), ident("-_1")]);
+        assert_eq!(tokens("1 - -x"), vec![Ok(Token::NumLit("1".to_string())), Ok(Token::Minus), ident("-x")]);
+    }
+
+    #[test]
+    fn test_rejected_identifiers() {
+        // identifiers cannot start with a digit, so these lex as a number followed by an identifier.
+        assert_eq!(tokens("01abc"), vec![Ok(Token::NumLit("01".to_string())), ident("abc")]);
+        assert_eq!(tokens("2x"), vec![Ok(Token::NumLit("2".to_string())), ident("x")]);
+        assert_eq!(tokens("-1"), vec![Ok(Token::NumLit("-1".to_string()))]);
+        assert_eq!(tokens("-"), vec![Ok(Token::Minus)]);
+        // an identifier needs at least one letter or underscore, so a run of hyphens is not one.
+        assert_eq!(tokens("--"), vec![Err(LexicalError(0, 2))]);
+    }
+}
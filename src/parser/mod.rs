@@ -5,6 +5,7 @@ use crate::{
     ast::SimplExpr,
     error::{Error, Result},
 };
+use lalrpop_util::ParseError;
 
 pub fn parse_string(file_id: usize, s: &str) -> Result<SimplExpr> {
     let lexer = lexer::Lexer::new(s);
@@ -12,6 +13,42 @@ pub fn parse_string(file_id: usize, s: &str) -> Result<SimplExpr> {
     parser.parse(file_id, lexer).map_err(|e| Error::from_parse_error(file_id, e))
 }
 
+/// Parse the longest valid expression at the start of the given string, without requiring the whole input to be consumed.
+/// Returns the parsed expression and the byte offset directly after it, where the trailing input begins.
+pub fn parse_partial(file_id: usize, s: &str) -> Result<(SimplExpr, usize)> {
+    let err = match parse_string(file_id, s) {
+        Ok(expr) => return Ok((expr, s.trim_end().len())),
+        Err(err) => err,
+    };
+    let stopped_at = match &err {
+        Error::ParseError { source, .. } => match source {
+            ParseError::UnrecognizedToken { token: (l, ..), .. } | ParseError::ExtraToken { token: (l, ..) } => *l,
+            ParseError::User { error: lexer::LexicalError(l, _) } => *l,
+            _ => return Err(err),
+        },
+        _ => return Err(err),
+    };
+    let prefix = &s[..stopped_at];
+    match parse_string(file_id, prefix) {
+        Ok(expr) => Ok((expr, prefix.trim_end().len())),
+        Err(_) => Err(err),
+    }
+}
+
+impl SimplExpr {
+    /// Parse the given string, returning an error containing the span of the offending input and the expected tokens on failure.
+    pub fn parse(src: &str) -> std::result::Result<SimplExpr, crate::error::ParseError> {
+        let lexer = lexer::Lexer::new(src);
+        let parser = crate::simplexpr_parser::ExprParser::new();
+        parser.parse(0, lexer).map_err(|e| crate::error::ParseError::from_lalrpop(0, src, e))
+    }
+
+    /// See [`parse_partial`].
+    pub fn parse_partial(file_id: usize, s: &str) -> Result<(SimplExpr, usize)> {
+        parse_partial(file_id, s)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     macro_rules! test_parser {
@@ -44,4 +81,86 @@ mod tests {
             "foo.bar[2 + 2] * asdf[foo.bar]",
         );
     }
+
+    #[test]
+    fn test_parse_partial() {
+        use super::parse_partial;
+        let (expr, offset) = parse_partial(0, "x + 1; other stuff").unwrap();
+        assert_eq!(format!("{}", expr), "(x + \"1\")");
+        assert_eq!(offset, 5);
+
+        let (expr, offset) = parse_partial(0, "foo(1, 2)  ) trailing").unwrap();
+        assert_eq!(format!("{}", expr), "foo(\"1\", \"2\")");
+        assert_eq!(offset, 9);
+
+        let input = "x + 1   ";
+        let (_, offset) = parse_partial(0, input).unwrap();
+        assert_eq!(offset, 5);
+        assert_eq!(&input[offset..], "   ");
+
+        assert!(parse_partial(0, "x + ; other stuff").is_err());
+    }
+
+    #[test]
+    fn test_precedence_matches_parser() {
+        use crate::ast::SimplExpr;
+        fn root_and_children(s: &str) -> (crate::ast::BinOp, Option<crate::ast::BinOp>, Option<crate::ast::BinOp>) {
+            let op_of = |e: &SimplExpr| match e {
+                SimplExpr::BinOp(_, _, op, _) => Some(op.clone()),
+                _ => None,
+            };
+            match super::parse_string(0, s).unwrap() {
+                SimplExpr::BinOp(_, l, op, r) => (op, op_of(&l), op_of(&r)),
+                other => panic!("expected a binary operation, got {}", other),
+            }
+        }
+
+        for input in &["a + b * c", "a * b + c", "a == b + c", "a && b == c", "a % b - c", "a =~ b || c"] {
+            let (root, left, right) = root_and_children(input);
+            for child in left.iter().chain(right.iter()) {
+                assert!(root.precedence() > child.precedence(), "{}: {} should bind looser than {}", input, root, child);
+            }
+        }
+
+        for input in &["a - b - c", "a / b * c", "a && b || c"] {
+            let (root, left, right) = root_and_children(input);
+            assert!(root.is_left_associative());
+            assert_eq!(left.map(|x| x.precedence()), Some(root.precedence()), "{}", input);
+            assert_eq!(right, None, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_comparison_operators() {
+        use crate::ast::{BinOp, SimplExpr};
+        let cases =
+            [("1 < 2", BinOp::LT, true), ("2 > 1", BinOp::GT, true), ("2 < 1", BinOp::LT, false), ("1 > 2", BinOp::GT, false)];
+        for (input, expected_op, expected_value) in cases.iter() {
+            let expr = super::parse_string(0, input).unwrap();
+            assert!(matches!(&expr, SimplExpr::BinOp(_, _, op, _) if op == expected_op), "{} parsed as {}", input, expr);
+            assert_eq!(expr.eval_no_vars().unwrap().as_bool().unwrap(), *expected_value, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_structured_parse_errors() {
+        use crate::{ast::Span, SimplExpr};
+        let err = SimplExpr::parse("foo(\"abc)").unwrap_err();
+        assert_eq!(err.message, "Unterminated string literal");
+        assert_eq!(err.span.0, 4);
+
+        let err = SimplExpr::parse("1 + * 2").unwrap_err();
+        assert_eq!(err.message, "Unexpected token `*`");
+        assert_eq!(err.span, Span(4, 5, 0));
+        assert!(err.expected.contains(&"(".to_string()), "{:?}", err.expected);
+
+        let err = SimplExpr::parse("1 + 2 )").unwrap_err();
+        assert_eq!(err.span, Span(6, 7, 0));
+        assert_eq!(err.message, "Unexpected trailing input `)`");
+
+        let err = SimplExpr::parse("1 +").unwrap_err();
+        assert_eq!(err.message, "Unexpected end of input");
+
+        assert!(SimplExpr::parse("1 + 2").is_ok());
+    }
 }
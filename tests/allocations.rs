@@ -0,0 +1,85 @@
+//! Checks on how much evaluating expressions allocates, using a global allocator that counts allocations.
+
+use simplexpr::dynval::DynVal;
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+    collections::HashMap,
+};
+
+struct CountingAllocator;
+
+thread_local! {
+    // counted per thread, so tests running in parallel do not see each other's allocations.
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// Run `f`, returning its result along with the number of allocations it made on the current thread.
+fn count_allocations<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    let before = ALLOCATIONS.with(Cell::get);
+    let result = f();
+    (result, ALLOCATIONS.with(Cell::get) - before)
+}
+
+/// Integral results are formatted directly, without first rounding them through another string.
+#[test]
+fn test_integral_floats_allocate_once() {
+    for x in [3.0, -42.0, 12345678.0].iter() {
+        let (_, allocations) = count_allocations(|| DynVal::from(*x));
+        assert_eq!(allocations, 1, "converting {} made {} allocations", x, allocations);
+    }
+}
+
+/// Compiling folds the constant subexpressions, so their results are not allocated on every evaluation.
+#[test]
+fn test_compiled_eval_allocates_less() {
+    let expr = simplexpr::parse_string(
+        0,
+        "(cpu * 100 / cores + mem_used / mem_total * 100) / 2 + round_num(temp - 32 * 5 / 9, 1) - 10 % 3",
+    )
+    .unwrap();
+    let mut vars = HashMap::new();
+    vars.insert("cpu".to_string(), DynVal::from(3.5));
+    vars.insert("cores".to_string(), DynVal::from(8));
+    vars.insert("mem_used".to_string(), DynVal::from(6144));
+    vars.insert("mem_total".to_string(), DynVal::from(16384));
+    vars.insert("temp".to_string(), DynVal::from(140));
+
+    let (_, eval_allocations) = count_allocations(|| expr.eval(&vars).unwrap());
+    let compiled = expr.compile();
+    let (_, compiled_allocations) = count_allocations(|| compiled.eval(&vars).unwrap());
+    assert!(compiled_allocations < eval_allocations, "{} >= {}", compiled_allocations, eval_allocations);
+}
+
+/// Cloning an expression allocates at most its boxed child nodes and literal values, and resolving it additionally only
+/// the resolved values. Variable names are shared rather than copied, so neither allocates per variable reference.
+#[test]
+fn test_allocations_per_resolve() {
+    let mut vars = HashMap::new();
+    vars.insert("volume".to_string(), DynVal::from(80));
+    vars.insert("label".to_string(), DynVal::from("vol: "));
+    vars.insert("muted".to_string(), DynVal::from(false));
+    let exprs = ["volume > 50 ? label + volume : muted ?: label", "round(volume / 3, 2) + \"%\"", "label"];
+    for src in exprs.iter() {
+        let expr = simplexpr::parse_string(0, src).unwrap();
+        let boxed_nodes = expr.node_count() - 1;
+        let (cloned, clone_allocations) = count_allocations(|| expr.clone());
+        assert!(clone_allocations <= boxed_nodes + expr.literal_values().len(), "{}: {}", src, clone_allocations);
+        let (_, resolve_allocations) = count_allocations(|| cloned.resolve_refs(&vars).unwrap());
+        assert!(resolve_allocations <= boxed_nodes + expr.var_refs().len(), "{}: {}", src, resolve_allocations);
+    }
+}